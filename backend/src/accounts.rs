@@ -0,0 +1,449 @@
+//! Accounts Module
+//!
+//! User accounts for self-hosted teams. Users live in the `users` collection
+//! with Argon2id password hashes; logging in issues an opaque session token
+//! (`lss_<40 characters>`) of which only a SHA-256 hash is stored in
+//! `sessions`, with a TTL index removing expired sessions.
+//!
+//! `SessionMiddleware` verifies `Authorization: Bearer lss_...` and stores
+//! the `User` in the request extensions; handlers read it with
+//! `Option<web::ReqData<User>>`. Links created by a user are owned by their
+//! id, so they can read their analytics and manage them under `/api/links`.
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, Error, HttpMessage, HttpResponse, ResponseError, Result};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use mongodb::bson::{doc, oid::ObjectId, DateTime as MongoDateTime};
+use mongodb::{Client, Collection};
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+use std::sync::OnceLock;
+use thiserror::Error;
+
+use crate::api_keys::{hash_key, random_alphanumeric};
+use crate::config::{AccountsConfig, Config};
+use crate::links::rfc3339;
+
+const TOKEN_PREFIX: &str = "lss_";
+const TOKEN_LENGTH: usize = 40;
+/// Bounds the hashing work a single request can cause
+const MAX_PASSWORD_LENGTH: usize = 1024;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AccountError {
+    #[error("Registration is closed")]
+    RegistrationClosed,
+    #[error("Invalid email address")]
+    InvalidEmail,
+    #[error("Passwords must be between {0} and {MAX_PASSWORD_LENGTH} characters")]
+    InvalidPassword(usize),
+    #[error("An account with this email already exists")]
+    EmailTaken,
+    #[error("Invalid email or password")]
+    InvalidCredentials,
+    #[error("Invalid or expired session")]
+    InvalidSession,
+    #[error("Not logged in")]
+    NotLoggedIn,
+}
+
+impl ResponseError for AccountError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AccountError::RegistrationClosed => StatusCode::FORBIDDEN,
+            AccountError::InvalidEmail | AccountError::InvalidPassword(_) => StatusCode::BAD_REQUEST,
+            AccountError::EmailTaken => StatusCode::CONFLICT,
+            AccountError::InvalidCredentials | AccountError::InvalidSession | AccountError::NotLoggedIn => {
+                StatusCode::UNAUTHORIZED
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).body(self.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserDoc {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// Lowercased, unique
+    pub email: String,
+    /// Argon2id PHC string
+    pub password_hash: String,
+    pub created_at: MongoDateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_changed_at: Option<MongoDateTime>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionDoc {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// Hex SHA-256 of the session token
+    pub token_hash: String,
+    pub user_id: ObjectId,
+    pub created_at: MongoDateTime,
+    /// Removed by a TTL index once passed
+    pub expires_at: MongoDateTime,
+}
+
+/// The logged-in user a request was made by
+#[derive(Debug, Clone)]
+pub struct User {
+    pub id: ObjectId,
+    pub email: String,
+    pub session_id: ObjectId,
+}
+
+impl User {
+    /// Links created by the user carry this as their `owner_id`
+    pub fn owner_id(&self) -> String {
+        self.id.to_hex()
+    }
+}
+
+fn users(client: &Client) -> Collection<UserDoc> {
+    client.database("shortener").collection("users")
+}
+
+fn sessions(client: &Client) -> Collection<SessionDoc> {
+    client.database("shortener").collection("sessions")
+}
+
+fn query_error(e: mongodb::error::Error) -> Error {
+    actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e))
+}
+
+/// Trimmed and lowercased; only checks the overall shape, delivery is not verified
+fn normalize_email(email: &str) -> Result<String, AccountError> {
+    let email = email.trim().to_lowercase();
+    let valid = email.len() <= 254
+        && !email.contains(char::is_whitespace)
+        && email.split_once('@').is_some_and(|(local, host)| !local.is_empty() && host.contains('.') && !host.contains('@'));
+    valid.then_some(email).ok_or(AccountError::InvalidEmail)
+}
+
+fn check_password(password: &str, settings: &AccountsConfig) -> Result<(), AccountError> {
+    let length = password.chars().count();
+    if length < settings.min_password_length || password.len() > MAX_PASSWORD_LENGTH {
+        return Err(AccountError::InvalidPassword(settings.min_password_length));
+    }
+    Ok(())
+}
+
+fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+}
+
+/// Hashing is deliberately slow, so it runs off the async workers
+async fn hash_password_blocking(password: String) -> Result<String, Error> {
+    web::block(move || hash_password(&password))
+        .await?
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Password hashing failed: {}", e)))
+}
+
+async fn verify_password_blocking(password: String, hash: String) -> Result<bool, Error> {
+    Ok(web::block(move || verify_password(&password, &hash)).await?)
+}
+
+/// Verified against for unknown emails, so login takes as long whether or not the account exists
+fn dummy_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| hash_password("not-a-real-password").expect("hashing a constant password"))
+}
+
+fn is_session_token(token: &str) -> bool {
+    token.len() == TOKEN_PREFIX.len() + TOKEN_LENGTH
+        && token.starts_with(TOKEN_PREFIX)
+        && token[TOKEN_PREFIX.len()..].bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+/// A bearer token with the session prefix; other bearer tokens are left alone
+fn presented_token(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && token.starts_with(TOKEN_PREFIX)).then(|| token.to_string())
+}
+
+async fn verify_session(client: &Client, token: &str) -> Result<User, Error> {
+    if !is_session_token(token) {
+        return Err(AccountError::InvalidSession.into());
+    }
+    // The TTL monitor runs about once a minute, so expiry is checked here as well
+    let session = sessions(client)
+        .find_one(doc! {"token_hash": hash_key(token), "expires_at": {"$gt": MongoDateTime::now()}}, None)
+        .await
+        .map_err(query_error)?
+        .ok_or(AccountError::InvalidSession)?;
+    let user = users(client)
+        .find_one(doc! {"_id": session.user_id}, None)
+        .await
+        .map_err(query_error)?
+        .ok_or(AccountError::InvalidSession)?;
+    Ok(User {
+        id: session.user_id,
+        email: user.email,
+        session_id: session.id.ok_or(AccountError::InvalidSession)?,
+    })
+}
+
+pub struct SessionMiddleware;
+
+impl<S, B> Transform<S, ServiceRequest> for SessionMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = SessionMiddlewareService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SessionMiddlewareService { service: Rc::new(service) }))
+    }
+}
+
+pub struct SessionMiddlewareService<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for SessionMiddlewareService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            if let Some(token) = presented_token(&req) {
+                let client = req
+                    .app_data::<web::Data<Client>>()
+                    .cloned()
+                    .ok_or_else(|| actix_web::error::ErrorInternalServerError("Database client not configured"))?;
+                let user = verify_session(&client, &token).await?;
+                tracing::debug!(user = %user.id, "Authenticated session");
+                req.extensions_mut().insert(user);
+            }
+            service.call(req).await
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CredentialsRequest {
+    email: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Serialize)]
+pub struct UserResponse {
+    id: String,
+    email: String,
+}
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    /// Sent as `Authorization: Bearer <token>`; only returned here
+    token: String,
+    expires_at: String,
+    user: UserResponse,
+}
+
+/// Start a session for `user_id`, returning the token and its expiry
+async fn create_session(client: &Client, user_id: ObjectId, settings: &AccountsConfig) -> Result<(String, MongoDateTime), Error> {
+    let token = format!("{}{}", TOKEN_PREFIX, random_alphanumeric(TOKEN_LENGTH));
+    let now = MongoDateTime::now();
+    let ttl_millis = settings.session_ttl_hours.saturating_mul(3_600_000).min(i64::MAX as u64) as i64;
+    let expires_at = MongoDateTime::from_millis(now.timestamp_millis().saturating_add(ttl_millis));
+    let session = SessionDoc { id: None, token_hash: hash_key(&token), user_id, created_at: now, expires_at };
+    sessions(client)
+        .insert_one(&session, None)
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Insert Error: {}", e)))?;
+    Ok((token, expires_at))
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(write_error)) if write_error.code == 11000
+    )
+}
+
+/// `POST /api/auth/register`: create an account and log it in
+pub async fn register(
+    client: web::Data<Client>,
+    settings: web::Data<Config>,
+    req: web::Json<CredentialsRequest>,
+) -> Result<HttpResponse> {
+    if !settings.accounts.registration_open {
+        return Err(AccountError::RegistrationClosed.into());
+    }
+    let req = req.into_inner();
+    let email = normalize_email(&req.email)?;
+    check_password(&req.password, &settings.accounts)?;
+    let user = UserDoc {
+        id: None,
+        email,
+        password_hash: hash_password_blocking(req.password).await?,
+        created_at: MongoDateTime::now(),
+        password_changed_at: None,
+    };
+    // The unique email index settles concurrent registrations
+    let user_id = match users(&client).insert_one(&user, None).await {
+        Ok(result) => result.inserted_id.as_object_id().ok_or_else(|| actix_web::error::ErrorInternalServerError("Unexpected user id"))?,
+        Err(e) if is_duplicate_key(&e) => return Err(AccountError::EmailTaken.into()),
+        Err(e) => return Err(actix_web::error::ErrorInternalServerError(format!("Insert Error: {}", e))),
+    };
+    tracing::info!(user = %user_id, "Registered user");
+    let (token, expires_at) = create_session(&client, user_id, &settings.accounts).await?;
+    Ok(HttpResponse::Created().json(SessionResponse {
+        token,
+        expires_at: rfc3339(expires_at),
+        user: UserResponse { id: user_id.to_hex(), email: user.email },
+    }))
+}
+
+/// `POST /api/auth/login`
+pub async fn login(
+    client: web::Data<Client>,
+    settings: web::Data<Config>,
+    req: web::Json<CredentialsRequest>,
+) -> Result<HttpResponse> {
+    let req = req.into_inner();
+    let email = normalize_email(&req.email).map_err(|_| AccountError::InvalidCredentials)?;
+    if req.password.len() > MAX_PASSWORD_LENGTH {
+        return Err(AccountError::InvalidCredentials.into());
+    }
+    let user = users(&client).find_one(doc! {"email": &email}, None).await.map_err(query_error)?;
+    let hash = user.as_ref().map_or_else(|| dummy_hash().to_string(), |user| user.password_hash.clone());
+    let verified = verify_password_blocking(req.password, hash).await?;
+    let (Some(user), true) = (user, verified) else {
+        return Err(AccountError::InvalidCredentials.into());
+    };
+    let user_id = user.id.ok_or(AccountError::InvalidCredentials)?;
+    let (token, expires_at) = create_session(&client, user_id, &settings.accounts).await?;
+    Ok(HttpResponse::Ok().json(SessionResponse {
+        token,
+        expires_at: rfc3339(expires_at),
+        user: UserResponse { id: user_id.to_hex(), email: user.email },
+    }))
+}
+
+/// `POST /api/auth/logout`: end the current session
+pub async fn logout(client: web::Data<Client>, user: Option<web::ReqData<User>>) -> Result<HttpResponse> {
+    let user = user.ok_or(AccountError::NotLoggedIn)?;
+    sessions(&client).delete_one(doc! {"_id": user.session_id}, None).await.map_err(query_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// `GET /api/auth/me`
+pub async fn me(user: Option<web::ReqData<User>>) -> Result<HttpResponse> {
+    let user = user.ok_or(AccountError::NotLoggedIn)?;
+    Ok(HttpResponse::Ok().json(UserResponse { id: user.id.to_hex(), email: user.email.clone() }))
+}
+
+/// `POST /api/auth/password`: requires the current password and ends every other session
+pub async fn change_password(
+    client: web::Data<Client>,
+    settings: web::Data<Config>,
+    user: Option<web::ReqData<User>>,
+    req: web::Json<ChangePasswordRequest>,
+) -> Result<HttpResponse> {
+    let user = user.ok_or(AccountError::NotLoggedIn)?;
+    let req = req.into_inner();
+    check_password(&req.new_password, &settings.accounts)?;
+    let stored = users(&client)
+        .find_one(doc! {"_id": user.id}, None)
+        .await
+        .map_err(query_error)?
+        .ok_or(AccountError::InvalidSession)?;
+    if req.current_password.len() > MAX_PASSWORD_LENGTH
+        || !verify_password_blocking(req.current_password, stored.password_hash).await?
+    {
+        return Err(AccountError::InvalidCredentials.into());
+    }
+    let password_hash = hash_password_blocking(req.new_password).await?;
+    users(&client)
+        .update_one(
+            doc! {"_id": user.id},
+            doc! {"$set": {"password_hash": password_hash, "password_changed_at": MongoDateTime::now()}},
+            None,
+        )
+        .await
+        .map_err(query_error)?;
+    let ended = sessions(&client)
+        .delete_many(doc! {"user_id": user.id, "_id": {"$ne": user.session_id}}, None)
+        .await
+        .map_err(query_error)?;
+    tracing::info!(user = %user.id, sessions_ended = ended.deleted_count, "Changed password");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_email() {
+        assert_eq!(normalize_email("  Ada@Example.COM ").unwrap(), "ada@example.com");
+        assert_eq!(normalize_email("ada@localhost"), Err(AccountError::InvalidEmail));
+        assert_eq!(normalize_email("@example.com"), Err(AccountError::InvalidEmail));
+        assert_eq!(normalize_email("a b@example.com"), Err(AccountError::InvalidEmail));
+        assert_eq!(normalize_email("a@b@example.com"), Err(AccountError::InvalidEmail));
+    }
+
+    #[test]
+    fn test_password_length() {
+        let settings = AccountsConfig::default();
+        assert_eq!(check_password("short", &settings), Err(AccountError::InvalidPassword(10)));
+        assert!(check_password("long enough password", &settings).is_ok());
+        assert!(check_password(&"x".repeat(MAX_PASSWORD_LENGTH + 1), &settings).is_err());
+    }
+
+    #[test]
+    fn test_password_hashing() {
+        let hash = hash_password("correct horse battery").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse battery", &hash));
+        assert!(!verify_password("wrong horse battery", &hash));
+        assert!(!verify_password("correct horse battery", "not a hash"));
+        assert_ne!(hash_password("correct horse battery").unwrap(), hash);
+    }
+
+    #[test]
+    fn test_session_token_shape() {
+        let token = format!("{}{}", TOKEN_PREFIX, random_alphanumeric(TOKEN_LENGTH));
+        assert!(is_session_token(&token));
+        assert!(!is_session_token("lss_short"));
+        assert!(!is_session_token(&format!("lsk_{}", random_alphanumeric(TOKEN_LENGTH))));
+    }
+}
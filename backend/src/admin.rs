@@ -0,0 +1,478 @@
+//! Admin Module
+//!
+//! Moderation tools for operators under `/api/admin`: searching links,
+//! banning destination domains and system stats. Disabling and deleting
+//! links share the status logic of the link management handlers, behind
+//! admin-only routes. Every endpoint needs the admin role: the API token, a
+//! JWT with `[jwt] admin_role`, or a user listed in `[accounts] admin_user_ids`
+//! (see `auth`).
+//!
+//! A banned domain also bans its subdomains. Shortening or editing a link to
+//! a banned host is refused, and banning can disable the existing links to
+//! it at the same time.
+
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpRequest, HttpResponse, ResponseError, Result};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, DateTime as MongoDateTime, Document, Regex};
+use mongodb::options::FindOptions;
+use mongodb::{Client, Collection};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::Url;
+
+use crate::auth::{Authenticated, LinkManager};
+use crate::click_buffer::{ClickBuffer, ClickStats};
+use crate::domains::Domains;
+use crate::links::{self, rfc3339, DisabledBy, LinkResponse, LinkStatus};
+use crate::public_url::PublicUrl;
+use crate::redirect_cache::{CacheStats, RedirectCache};
+use crate::{DomainQuery, UrlDoc};
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AdminError {
+    #[error("Links to {0} are not allowed")]
+    BannedDomain(String),
+    #[error("Invalid domain: {0}")]
+    InvalidDomain(String),
+    #[error("Invalid workspace id")]
+    InvalidWorkspaceId,
+}
+
+impl ResponseError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).body(self.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BannedDomainDoc {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// Lowercase host name, without a trailing dot
+    pub domain: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub created_at: MongoDateTime,
+}
+
+fn banned_domains(client: &Client) -> Collection<BannedDomainDoc> {
+    client.database("shortener").collection("banned_domains")
+}
+
+fn query_error(e: mongodb::error::Error) -> Error {
+    actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e))
+}
+
+/// Lowercased, without a trailing dot; rejects anything that is not a plain host name
+fn normalize_domain(domain: &str) -> Result<String, AdminError> {
+    let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    let valid = !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-'));
+    valid.then_some(domain.clone()).ok_or(AdminError::InvalidDomain(domain))
+}
+
+/// The host and every parent domain, e.g. `a.b.com`, `b.com`, `com`
+fn host_and_parents(host: &str) -> Vec<String> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let mut candidates = vec![host.clone()];
+    let mut rest = host.as_str();
+    while let Some((_, parent)) = rest.split_once('.') {
+        candidates.push(parent.to_string());
+        rest = parent;
+    }
+    candidates
+}
+
+fn is_within(host: &str, domain: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    host == domain || host.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+fn host_of(url: &str) -> Option<String> {
+    Url::parse(url).ok().and_then(|parsed| parsed.host_str().map(str::to_string))
+}
+
+/// The banned domain each destination falls under, if any, looked up with a single query
+pub async fn banned_destinations(client: &Client, urls: &[&str]) -> Result<Vec<Option<String>>, Error> {
+    let hosts: Vec<Option<String>> = urls.iter().map(|url| host_of(url)).collect();
+    let candidates: Vec<String> = hosts.iter().flatten().flat_map(|host| host_and_parents(host)).collect();
+    if candidates.is_empty() {
+        return Ok(vec![None; urls.len()]);
+    }
+    let banned: Vec<BannedDomainDoc> = banned_domains(client)
+        .find(doc! {"domain": {"$in": candidates}}, None)
+        .await
+        .map_err(query_error)?
+        .try_collect()
+        .await
+        .map_err(query_error)?;
+    Ok(hosts
+        .iter()
+        .map(|host| {
+            let host = host.as_deref()?;
+            banned.iter().find(|ban| is_within(host, &ban.domain)).map(|ban| ban.domain.clone())
+        })
+        .collect())
+}
+
+/// Refuse destinations on a banned domain or one of its subdomains
+pub async fn check_destination(client: &Client, url: &str) -> Result<(), Error> {
+    match banned_destinations(client, &[url]).await?.pop().flatten() {
+        Some(domain) => Err(AdminError::BannedDomain(domain).into()),
+        None => Ok(()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    /// Short code prefix
+    code: Option<String>,
+    /// Case-insensitive substring of the destination
+    url: Option<String>,
+    owner_id: Option<String>,
+    workspace_id: Option<String>,
+    domain: Option<String>,
+    status: Option<LinkStatus>,
+    limit: Option<i64>,
+}
+
+pub fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn search_filter(query: &SearchQuery, domains: &Domains) -> Result<Document, Error> {
+    let mut filter = doc! {};
+    if let Some(code) = query.code.as_deref().filter(|c| !c.is_empty()) {
+        filter.insert("short_code", Regex { pattern: format!("^{}", escape_regex(code)), options: String::new() });
+    }
+    if let Some(url) = query.url.as_deref().filter(|u| !u.is_empty()) {
+        filter.insert("original_url", Regex { pattern: escape_regex(url), options: "i".into() });
+    }
+    if let Some(owner_id) = &query.owner_id {
+        filter.insert("owner_id", owner_id);
+    }
+    if let Some(workspace_id) = &query.workspace_id {
+        filter.insert("workspace_id", ObjectId::parse_str(workspace_id).map_err(|_| AdminError::InvalidWorkspaceId)?);
+    }
+    if let Some(name) = &query.domain {
+        let domain = domains.by_name(Some(name)).map_err(actix_web::error::ErrorBadRequest)?;
+        filter.insert("domain", domain.filter_value());
+    }
+    match query.status {
+        Some(LinkStatus::Active) => filter.insert("status", mongodb::bson::Bson::Null),
+        Some(LinkStatus::Disabled) => filter.insert("status", "disabled"),
+        Some(LinkStatus::Deleted) => filter.insert("status", "deleted"),
+        None => None,
+    };
+    Ok(filter)
+}
+
+/// `GET /api/admin/links`: newest first, including disabled and deleted links
+pub async fn search_links(
+    _auth: Authenticated,
+    client: web::Data<Client>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    query: web::Query<SearchQuery>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse> {
+    let filter = search_filter(&query, &domains)?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let options = FindOptions::builder().sort(doc! {"created_at": -1}).limit(limit).build();
+    let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
+    let found: Vec<UrlDoc> =
+        collection.find(filter, options).await.map_err(query_error)?.try_collect().await.map_err(query_error)?;
+    let links: Vec<LinkResponse> = found
+        .iter()
+        .map(|url_doc| {
+            // Links on a domain that is no longer configured are shown on the primary one
+            let domain = domains.by_name(url_doc.domain.as_deref()).unwrap_or_else(|_| domains.primary());
+            LinkResponse::new(url_doc, domain, &public_url, &http_req)
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(links))
+}
+
+/// `POST /api/admin/links/{short_code}/disable`
+#[allow(clippy::too_many_arguments)]
+pub async fn disable_link(
+    _auth: Authenticated,
+    client: web::Data<Client>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    cache: web::Data<RedirectCache>,
+    path: web::Path<String>,
+    query: web::Query<DomainQuery>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse> {
+    let short_code = path.into_inner();
+    links::change_status(LinkManager::Operator, client, public_url, domains, cache, short_code, query, http_req, LinkStatus::Disabled).await
+}
+
+/// `POST /api/admin/links/{short_code}/enable`
+#[allow(clippy::too_many_arguments)]
+pub async fn enable_link(
+    _auth: Authenticated,
+    client: web::Data<Client>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    cache: web::Data<RedirectCache>,
+    path: web::Path<String>,
+    query: web::Query<DomainQuery>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse> {
+    let short_code = path.into_inner();
+    links::change_status(LinkManager::Operator, client, public_url, domains, cache, short_code, query, http_req, LinkStatus::Active).await
+}
+
+/// `DELETE /api/admin/links/{short_code}`
+#[allow(clippy::too_many_arguments)]
+pub async fn delete_link(
+    _auth: Authenticated,
+    client: web::Data<Client>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    cache: web::Data<RedirectCache>,
+    path: web::Path<String>,
+    query: web::Query<DomainQuery>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse> {
+    let short_code = path.into_inner();
+    links::change_status(LinkManager::Operator, client, public_url, domains, cache, short_code, query, http_req, LinkStatus::Deleted).await
+}
+
+#[derive(Deserialize)]
+pub struct BanDomainRequest {
+    domain: String,
+    #[serde(default)]
+    reason: Option<String>,
+    /// Also disable active links to the domain and its subdomains
+    #[serde(default)]
+    disable_existing: bool,
+}
+
+#[derive(Serialize)]
+pub struct BannedDomainResponse {
+    domain: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    created_at: String,
+    /// Only set when banning with `disable_existing`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disabled_links: Option<u64>,
+}
+
+impl BannedDomainResponse {
+    fn new(doc: BannedDomainDoc, disabled_links: Option<u64>) -> Self {
+        BannedDomainResponse { domain: doc.domain, reason: doc.reason, created_at: rfc3339(doc.created_at), disabled_links }
+    }
+}
+
+/// Disable the active links pointing at `domain` or its subdomains; returns how many were disabled
+async fn disable_links_to(client: &Client, domains: &Domains, cache: &RedirectCache, domain: &str) -> Result<u64, Error> {
+    let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
+    // The regex narrows the scan, the parsed host decides
+    let pattern = format!(r"^https?://([^/?#]*@)?([^/?#@]*\.)?{}(:[0-9]+)?([/?#]|$)", escape_regex(domain));
+    let filter = doc! {"original_url": Regex { pattern, options: "i".into() }, "status": null};
+    let candidates: Vec<UrlDoc> =
+        collection.find(filter, None).await.map_err(query_error)?.try_collect().await.map_err(query_error)?;
+    let matching: Vec<&UrlDoc> = candidates
+        .iter()
+        .filter(|url_doc| {
+            Url::parse(&url_doc.original_url).ok().and_then(|u| u.host_str().map(|h| is_within(h, domain))).unwrap_or(false)
+        })
+        .collect();
+    let ids: Vec<ObjectId> = matching.iter().filter_map(|url_doc| url_doc.id).collect();
+    if ids.is_empty() {
+        return Ok(0);
+    }
+    let result = collection
+        .update_many(doc! {"_id": {"$in": ids}, "status": null}, doc! {"$set": {"status": "disabled", "disabled_by": DisabledBy::Admin.as_str()}}, None)
+        .await
+        .map_err(query_error)?;
+    for url_doc in matching {
+        let name = url_doc.domain.clone().unwrap_or_else(|| domains.primary().name.clone());
+        cache.invalidate(&name, &url_doc.short_code);
+    }
+    Ok(result.modified_count)
+}
+
+/// `POST /api/admin/banned-domains`
+pub async fn ban_domain(
+    _auth: Authenticated,
+    client: web::Data<Client>,
+    domains: web::Data<Domains>,
+    cache: web::Data<RedirectCache>,
+    req: web::Json<BanDomainRequest>,
+) -> Result<HttpResponse> {
+    let req = req.into_inner();
+    let domain = normalize_domain(&req.domain)?;
+    let doc = BannedDomainDoc {
+        id: None,
+        domain: domain.clone(),
+        reason: req.reason.map(|r| r.trim().to_string()).filter(|r| !r.is_empty()),
+        created_at: MongoDateTime::now(),
+    };
+    // Banning twice keeps the first ban
+    let options = mongodb::options::UpdateOptions::builder().upsert(true).build();
+    let update = doc! {"$setOnInsert": mongodb::bson::to_document(&doc).map_err(actix_web::error::ErrorInternalServerError)?};
+    banned_domains(&client).update_one(doc! {"domain": &domain}, update, options).await.map_err(query_error)?;
+    let disabled_links = match req.disable_existing {
+        true => Some(disable_links_to(&client, &domains, &cache, &domain).await?),
+        false => None,
+    };
+    tracing::info!(domain = %domain, disabled_links = ?disabled_links, "Banned domain");
+    Ok(HttpResponse::Created().json(BannedDomainResponse::new(doc, disabled_links)))
+}
+
+/// `GET /api/admin/banned-domains`
+pub async fn list_banned_domains(_auth: Authenticated, client: web::Data<Client>) -> Result<HttpResponse> {
+    let options = FindOptions::builder().sort(doc! {"domain": 1}).build();
+    let found: Vec<BannedDomainDoc> =
+        banned_domains(&client).find(doc! {}, options).await.map_err(query_error)?.try_collect().await.map_err(query_error)?;
+    Ok(HttpResponse::Ok().json(found.into_iter().map(|doc| BannedDomainResponse::new(doc, None)).collect::<Vec<_>>()))
+}
+
+/// `DELETE /api/admin/banned-domains/{domain}`: links disabled by the ban stay disabled
+pub async fn unban_domain(_auth: Authenticated, client: web::Data<Client>, path: web::Path<String>) -> Result<HttpResponse> {
+    let domain = normalize_domain(&path)?;
+    let result = banned_domains(&client).delete_one(doc! {"domain": &domain}, None).await.map_err(query_error)?;
+    if result.deleted_count == 0 {
+        return Ok(HttpResponse::NotFound().body("Domain is not banned"));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Serialize)]
+pub struct LinkCounts {
+    total: u64,
+    active: u64,
+    disabled: u64,
+    deleted: u64,
+    created_last_24h: u64,
+}
+
+#[derive(Serialize)]
+pub struct StatsResponse {
+    links: LinkCounts,
+    total_clicks: i64,
+    users: u64,
+    workspaces: u64,
+    active_api_keys: u64,
+    banned_domains: u64,
+    cache: CacheStats,
+    clicks: ClickStats,
+}
+
+/// `GET /api/admin/stats`
+pub async fn stats(
+    _auth: Authenticated,
+    client: web::Data<Client>,
+    cache: web::Data<RedirectCache>,
+    clicks: web::Data<ClickBuffer>,
+) -> Result<HttpResponse> {
+    let db = client.database("shortener");
+    let urls = db.collection::<Document>("urls");
+    let count = |collection: &str, filter: Document| {
+        let collection = db.collection::<Document>(collection);
+        async move { collection.count_documents(filter, None).await.map_err(query_error) }
+    };
+    let day_ago = MongoDateTime::from_millis(MongoDateTime::now().timestamp_millis() - 24 * 3_600_000);
+    let links = LinkCounts {
+        total: count("urls", doc! {}).await?,
+        active: count("urls", doc! {"status": null}).await?,
+        disabled: count("urls", doc! {"status": "disabled"}).await?,
+        deleted: count("urls", doc! {"status": "deleted"}).await?,
+        created_last_24h: count("urls", doc! {"created_at": {"$gte": day_ago}}).await?,
+    };
+    let totals: Vec<Document> = urls
+        .aggregate([doc! {"$group": {"_id": null, "clicks": {"$sum": "$transition_count"}}}], None)
+        .await
+        .map_err(query_error)?
+        .try_collect()
+        .await
+        .map_err(query_error)?;
+    let total_clicks = totals.first().and_then(|totals| match totals.get("clicks") {
+        Some(mongodb::bson::Bson::Int64(n)) => Some(*n),
+        Some(mongodb::bson::Bson::Int32(n)) => Some(i64::from(*n)),
+        _ => None,
+    });
+    Ok(HttpResponse::Ok().json(StatsResponse {
+        links,
+        total_clicks: total_clicks.unwrap_or(0),
+        users: count("users", doc! {}).await?,
+        workspaces: count("workspaces", doc! {}).await?,
+        active_api_keys: count("api_keys", doc! {"revoked_at": null}).await?,
+        banned_domains: count("banned_domains", doc! {}).await?,
+        cache: cache.stats(),
+        clicks: clicks.stats(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_domain() {
+        assert_eq!(normalize_domain(" Evil.Example. ").unwrap(), "evil.example");
+        assert!(normalize_domain("evil..example").is_err());
+        assert!(normalize_domain("https://evil.example").is_err());
+        assert!(normalize_domain("").is_err());
+    }
+
+    #[test]
+    fn test_host_and_parents() {
+        assert_eq!(host_and_parents("A.b.example."), vec!["a.b.example", "b.example", "example"]);
+        assert_eq!(host_and_parents("localhost"), vec!["localhost"]);
+    }
+
+    #[test]
+    fn test_is_within() {
+        assert!(is_within("evil.example", "evil.example"));
+        assert!(is_within("cdn.Evil.example", "evil.example"));
+        assert!(!is_within("notevil.example", "evil.example"));
+        assert!(!is_within("evil.example.org", "evil.example"));
+    }
+
+    #[test]
+    fn test_search_filter() {
+        let domains = Domains::new(&[]).unwrap();
+        let query = SearchQuery {
+            code: Some("ab.".into()),
+            url: Some("Example.com/a+b".into()),
+            owner_id: Some("team-a".into()),
+            workspace_id: None,
+            domain: None,
+            status: Some(LinkStatus::Disabled),
+            limit: None,
+        };
+        let filter = search_filter(&query, &domains).unwrap();
+        let pattern = |key: &str| match filter.get(key) {
+            Some(mongodb::bson::Bson::RegularExpression(regex)) => regex.pattern.clone(),
+            other => panic!("expected a regex, got {:?}", other),
+        };
+        assert_eq!(pattern("short_code"), r"^ab\.");
+        assert_eq!(pattern("original_url"), r"Example\.com/a\+b");
+        assert_eq!(filter.get_str("owner_id").unwrap(), "team-a");
+        assert_eq!(filter.get_str("status").unwrap(), "disabled");
+        let query = SearchQuery { workspace_id: Some("nope".into()), ..query };
+        assert!(search_filter(&query, &domains).is_err());
+    }
+}
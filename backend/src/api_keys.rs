@@ -0,0 +1,387 @@
+//! API Keys Module
+//!
+//! API keys look like `lsk_<8 character id>_<32 character secret>`. Only the
+//! `lsk_<id>` prefix and a SHA-256 hash of the whole key are stored, so a
+//! leaked database does not leak usable keys, while the prefix still tells
+//! operators which key is which. Each key belongs to an owner and carries
+//! `read` and/or `write` scopes.
+//!
+//! `ApiKeyMiddleware` verifies a key sent as `X-API-Key` or as an
+//! `Authorization: Bearer lsk_...` header and stores the `ApiKey` in the
+//! request extensions; handlers read it with `Option<web::ReqData<ApiKey>>`.
+//! Requests without a key pass through anonymously; requests with a bad key
+//! are rejected. Keys are managed under `/api/keys` with the API token.
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, Error, HttpMessage, HttpResponse, ResponseError, Result};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, DateTime as MongoDateTime};
+use mongodb::options::FindOptions;
+use mongodb::{Client, Collection};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::rc::Rc;
+use thiserror::Error;
+
+use crate::auth::{constant_time_eq, Authenticated};
+use crate::links::rfc3339;
+
+const KEY_PREFIX: &str = "lsk_";
+const ID_LENGTH: usize = 8;
+const SECRET_LENGTH: usize = 32;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ApiKeyError {
+    #[error("An API key is required")]
+    Missing,
+    #[error("Invalid or revoked API key")]
+    Invalid,
+    #[error("The API key lacks the {0} scope")]
+    MissingScope(&'static str),
+}
+
+impl ResponseError for ApiKeyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiKeyError::Missing | ApiKeyError::Invalid => StatusCode::UNAUTHORIZED,
+            ApiKeyError::MissingScope(_) => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).body(self.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Read analytics of the owner's links
+    Read,
+    /// Create links
+    Write,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyDoc {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// `lsk_<id>`, unique and safe to display
+    pub prefix: String,
+    /// Hex SHA-256 of the full key
+    pub hash: String,
+    pub name: String,
+    pub owner_id: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: MongoDateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<MongoDateTime>,
+}
+
+/// The verified key a request was made with
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub id: ObjectId,
+    pub prefix: String,
+    pub owner_id: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl ApiKey {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+pub fn random_alphanumeric(len: usize) -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(len).map(char::from).collect()
+}
+
+/// Hex SHA-256, for secrets looked up by hash rather than verified slowly
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes()).iter().fold(String::with_capacity(64), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+/// A new key and its stored prefix
+fn generate_key() -> (String, String) {
+    let prefix = format!("{}{}", KEY_PREFIX, random_alphanumeric(ID_LENGTH));
+    let key = format!("{}_{}", prefix, random_alphanumeric(SECRET_LENGTH));
+    (key, prefix)
+}
+
+/// The stored prefix of a well-formed key
+fn key_prefix(key: &str) -> Option<&str> {
+    let prefix_len = KEY_PREFIX.len() + ID_LENGTH;
+    let well_formed = key.starts_with(KEY_PREFIX)
+        && key.len() == prefix_len + 1 + SECRET_LENGTH
+        && key.as_bytes()[prefix_len] == b'_'
+        && key[KEY_PREFIX.len()..].bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_');
+    well_formed.then(|| &key[..prefix_len])
+}
+
+/// A key sent as `X-API-Key`, or as a bearer token with the key prefix (other bearer tokens are left alone)
+fn presented_key(req: &ServiceRequest) -> Option<String> {
+    if let Some(value) = req.headers().get("X-API-Key") {
+        return Some(value.to_str().unwrap_or_default().trim().to_string());
+    }
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && token.starts_with(KEY_PREFIX)).then(|| token.to_string())
+}
+
+async fn verify(client: &Client, key: &str) -> Result<ApiKey, Error> {
+    let prefix = key_prefix(key).ok_or(ApiKeyError::Invalid)?;
+    let collection: Collection<ApiKeyDoc> = client.database("shortener").collection("api_keys");
+    let stored = collection
+        .find_one(doc! {"prefix": prefix, "revoked_at": null}, None)
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e)))?
+        .ok_or(ApiKeyError::Invalid)?;
+    if !constant_time_eq(hash_key(key).as_bytes(), stored.hash.as_bytes()) {
+        return Err(ApiKeyError::Invalid.into());
+    }
+    Ok(ApiKey {
+        id: stored.id.ok_or(ApiKeyError::Invalid)?,
+        prefix: stored.prefix,
+        owner_id: stored.owner_id,
+        scopes: stored.scopes,
+    })
+}
+
+/// Check the caller may use an endpoint needing `scope`; anonymous callers pass unless a key is required
+pub fn authorize(key: Option<&ApiKey>, scope: Scope, required: bool) -> Result<Option<&ApiKey>, ApiKeyError> {
+    match key {
+        Some(key) if !key.has_scope(scope) => Err(ApiKeyError::MissingScope(scope.as_str())),
+        None if required => Err(ApiKeyError::Missing),
+        key => Ok(key),
+    }
+}
+
+pub struct ApiKeyMiddleware;
+
+impl<S, B> Transform<S, ServiceRequest> for ApiKeyMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ApiKeyMiddlewareService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ApiKeyMiddlewareService { service: Rc::new(service) }))
+    }
+}
+
+pub struct ApiKeyMiddlewareService<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for ApiKeyMiddlewareService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            if let Some(key) = presented_key(&req) {
+                let client = req
+                    .app_data::<web::Data<Client>>()
+                    .cloned()
+                    .ok_or_else(|| actix_web::error::ErrorInternalServerError("Database client not configured"))?;
+                let api_key = verify(&client, &key).await?;
+                tracing::debug!(key = %api_key.prefix, "Authenticated API key");
+                req.extensions_mut().insert(api_key);
+            }
+            service.call(req).await
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateKeyRequest {
+    name: String,
+    owner_id: String,
+    scopes: Vec<Scope>,
+}
+
+#[derive(Serialize)]
+pub struct KeyResponse {
+    prefix: String,
+    name: String,
+    owner_id: String,
+    scopes: Vec<Scope>,
+    created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revoked_at: Option<String>,
+    /// Only returned when the key is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+}
+
+impl KeyResponse {
+    fn new(doc: ApiKeyDoc, key: Option<String>) -> Self {
+        KeyResponse {
+            prefix: doc.prefix,
+            name: doc.name,
+            owner_id: doc.owner_id,
+            scopes: doc.scopes,
+            created_at: rfc3339(doc.created_at),
+            revoked_at: doc.revoked_at.map(rfc3339),
+            key,
+        }
+    }
+}
+
+/// `POST /api/keys`: the plaintext key is returned once and cannot be recovered
+pub async fn create_key(
+    _auth: Authenticated,
+    client: web::Data<Client>,
+    req: web::Json<CreateKeyRequest>,
+) -> Result<HttpResponse> {
+    let req = req.into_inner();
+    if req.name.trim().is_empty() || req.owner_id.trim().is_empty() {
+        return Ok(HttpResponse::BadRequest().body("name and owner_id must not be empty"));
+    }
+    if req.scopes.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("At least one scope is required"));
+    }
+    let (key, prefix) = generate_key();
+    let doc = ApiKeyDoc {
+        id: None,
+        prefix,
+        hash: hash_key(&key),
+        name: req.name.trim().to_string(),
+        owner_id: req.owner_id.trim().to_string(),
+        scopes: req.scopes,
+        created_at: MongoDateTime::now(),
+        revoked_at: None,
+    };
+    let collection: Collection<ApiKeyDoc> = client.database("shortener").collection("api_keys");
+    collection
+        .insert_one(&doc, None)
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Insert Error: {}", e)))?;
+    Ok(HttpResponse::Created().json(KeyResponse::new(doc, Some(key))))
+}
+
+#[derive(Deserialize)]
+pub struct ListKeysQuery {
+    owner_id: Option<String>,
+}
+
+/// `GET /api/keys`, optionally for one owner
+pub async fn list_keys(
+    _auth: Authenticated,
+    client: web::Data<Client>,
+    query: web::Query<ListKeysQuery>,
+) -> Result<HttpResponse> {
+    let filter = match &query.owner_id {
+        Some(owner_id) => doc! {"owner_id": owner_id},
+        None => doc! {},
+    };
+    let collection: Collection<ApiKeyDoc> = client.database("shortener").collection("api_keys");
+    let options = FindOptions::builder().sort(doc! {"created_at": -1}).build();
+    let keys: Vec<ApiKeyDoc> = collection
+        .find(filter, options)
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e)))?
+        .try_collect()
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e)))?;
+    Ok(HttpResponse::Ok().json(keys.into_iter().map(|doc| KeyResponse::new(doc, None)).collect::<Vec<_>>()))
+}
+
+/// `DELETE /api/keys/{prefix}`: revoked keys stop working at once but are kept for reference
+pub async fn revoke_key(
+    _auth: Authenticated,
+    client: web::Data<Client>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let collection: Collection<ApiKeyDoc> = client.database("shortener").collection("api_keys");
+    let result = collection
+        .update_one(
+            doc! {"prefix": path.into_inner(), "revoked_at": null},
+            doc! {"$set": {"revoked_at": MongoDateTime::now()}},
+            None,
+        )
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e)))?;
+    if result.matched_count == 0 {
+        return Ok(HttpResponse::NotFound().body("API key not found"));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(scopes: &[Scope]) -> ApiKey {
+        ApiKey { id: ObjectId::new(), prefix: "lsk_abcd1234".into(), owner_id: "team-a".into(), scopes: scopes.to_vec() }
+    }
+
+    #[test]
+    fn test_generated_keys_are_well_formed() {
+        let (key, prefix) = generate_key();
+        assert_eq!(key_prefix(&key), Some(prefix.as_str()));
+        assert!(key.starts_with(&format!("{}_", prefix)));
+        assert_eq!(hash_key(&key).len(), 64);
+        assert_ne!(generate_key().0, key);
+    }
+
+    #[test]
+    fn test_malformed_keys_rejected() {
+        assert_eq!(key_prefix("lsk_abcd1234"), None);
+        assert_eq!(key_prefix(&format!("lsk_abcd1234-{}", "x".repeat(SECRET_LENGTH))), None);
+        assert_eq!(key_prefix(&format!("sk_abcd12345_{}", "x".repeat(SECRET_LENGTH))), None);
+        assert_eq!(key_prefix(&format!("lsk_abcd1234_{}", "é".repeat(SECRET_LENGTH / 2))), None);
+    }
+
+    #[test]
+    fn test_hash_is_stable() {
+        assert_eq!(hash_key("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn test_authorize() {
+        let read_only = key(&[Scope::Read]);
+        assert_eq!(authorize(Some(&read_only), Scope::Write, false).unwrap_err(), ApiKeyError::MissingScope("write"));
+        assert!(authorize(Some(&read_only), Scope::Read, true).unwrap().is_some());
+        assert!(authorize(None, Scope::Write, false).unwrap().is_none());
+        assert_eq!(authorize(None, Scope::Write, true).unwrap_err(), ApiKeyError::Missing);
+    }
+
+    #[test]
+    fn test_scopes_serialize_lowercase() {
+        assert_eq!(serde_json::to_string(&[Scope::Read, Scope::Write]).unwrap(), r#"["read","write"]"#);
+    }
+}
@@ -0,0 +1,218 @@
+//! Auth Module
+//!
+//! Guards operator endpoints with the bearer token configured as
+//! `[auth] api_token`, a verified JWT carrying `[jwt] admin_role`, or the
+//! session of a user listed in `[accounts] admin_user_ids`.
+//! Handlers opt in by taking an `Authenticated` argument. Without a
+//! configured token or JWT verification those endpoints are disabled.
+//! Link management takes a `LinkManager` instead, which also lets logged-in
+//! users manage the links they own and, as editors, their workspaces' links.
+
+use actix_web::dev::Payload;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use mongodb::bson::oid::ObjectId;
+use mongodb::Client;
+use std::future::{ready, Ready};
+use thiserror::Error;
+
+use crate::accounts::User;
+use crate::config::Config;
+use crate::jwt::Claims;
+use crate::workspaces::{self, Role};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AuthError {
+    /// No API token is configured and the caller has no admin role from a JWT or an admin session
+    #[error("Admin role required: sign in with an admin JWT or an admin user's session (no API token is configured)")]
+    Disabled,
+    #[error("Missing bearer token")]
+    Missing,
+    #[error("Invalid bearer token")]
+    Invalid,
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Disabled => StatusCode::FORBIDDEN,
+            AuthError::Missing | AuthError::Invalid => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if self.status_code() == StatusCode::UNAUTHORIZED {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.body(self.to_string())
+    }
+}
+
+/// Proof that the caller has the admin role: the configured API token, a JWT
+/// with `[jwt] admin_role`, or the session of a user in `[accounts] admin_user_ids`
+pub struct Authenticated;
+
+/// Compare without short-circuiting, so timing does not reveal how much of the token matched
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+fn authenticate(req: &HttpRequest) -> Result<Authenticated, AuthError> {
+    let config = req.app_data::<web::Data<Config>>().ok_or(AuthError::Disabled)?;
+    if let Some(claims) = req.extensions().get::<Claims>() {
+        if claims.has_role(&config.jwt.admin_role) {
+            return Ok(Authenticated);
+        }
+    }
+    if let Some(user) = req.extensions().get::<User>() {
+        if config.accounts.admin_user_ids.iter().any(|id| *id == user.id.to_hex()) {
+            return Ok(Authenticated);
+        }
+    }
+    let expected = config.auth.api_token.clone().ok_or(AuthError::Disabled)?;
+    let token = bearer_token(req).ok_or(AuthError::Missing)?;
+    if constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+        Ok(Authenticated)
+    } else {
+        Err(AuthError::Invalid)
+    }
+}
+
+impl FromRequest for Authenticated {
+    type Error = AuthError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authenticate(req))
+    }
+}
+
+/// Who is managing a link: operators may manage every link, users only their own and their workspaces'
+#[derive(Debug, PartialEq)]
+pub enum LinkManager {
+    Operator,
+    User(ObjectId),
+}
+
+impl LinkManager {
+    /// Whether the caller owns a link outside any workspace
+    fn owns(&self, owner_id: Option<&str>) -> bool {
+        match self {
+            LinkManager::Operator => true,
+            LinkManager::User(user_id) => owner_id == Some(user_id.to_hex().as_str()),
+        }
+    }
+
+    /// Workspace links need the editor role, other links the same owner
+    pub async fn may_manage(
+        &self,
+        client: &Client,
+        owner_id: Option<&str>,
+        workspace_id: Option<ObjectId>,
+    ) -> Result<bool, mongodb::error::Error> {
+        match (self, workspace_id) {
+            (LinkManager::User(user_id), Some(workspace_id)) => {
+                Ok(workspaces::role_of(client, workspace_id, *user_id).await?.is_some_and(|role| role >= Role::Editor))
+            }
+            _ => Ok(self.owns(owner_id)),
+        }
+    }
+}
+
+impl FromRequest for LinkManager {
+    type Error = AuthError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let manager = authenticate(req).map(|_| LinkManager::Operator).or_else(|e| {
+            req.extensions().get::<User>().map(|user| LinkManager::User(user.id)).ok_or(e)
+        });
+        ready(manager)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn config(token: Option<&str>) -> web::Data<Config> {
+        let mut config = Config::default();
+        config.auth.api_token = token.map(String::from);
+        web::Data::new(config)
+    }
+
+    #[test]
+    fn test_disabled_without_token() {
+        let req = TestRequest::default().app_data(config(None)).insert_header(("Authorization", "Bearer x")).to_http_request();
+        assert_eq!(authenticate(&req).err(), Some(AuthError::Disabled));
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let token = "0123456789abcdef0123";
+        let req = TestRequest::default().app_data(config(Some(token))).to_http_request();
+        assert_eq!(authenticate(&req).err(), Some(AuthError::Missing));
+        let req = TestRequest::default()
+            .app_data(config(Some(token)))
+            .insert_header(("Authorization", "Bearer wrong"))
+            .to_http_request();
+        assert_eq!(authenticate(&req).err(), Some(AuthError::Invalid));
+        let req = TestRequest::default()
+            .app_data(config(Some(token)))
+            .insert_header(("Authorization", format!("bearer {}", token)))
+            .to_http_request();
+        assert!(authenticate(&req).is_ok());
+    }
+
+    #[test]
+    fn test_admin_jwt_claims() {
+        let req = TestRequest::default().app_data(config(None)).to_http_request();
+        req.extensions_mut().insert(Claims { subject: "ops".into(), roles: vec!["admin".into()] });
+        assert!(authenticate(&req).is_ok());
+        let req = TestRequest::default().app_data(config(None)).to_http_request();
+        req.extensions_mut().insert(Claims { subject: "user".into(), roles: vec!["user".into()] });
+        assert_eq!(authenticate(&req).err(), Some(AuthError::Disabled));
+    }
+
+    #[test]
+    fn test_admin_users() {
+        let admin_id = ObjectId::new();
+        let mut settings = Config::default();
+        settings.accounts.admin_user_ids = vec![admin_id.to_hex()];
+        let settings = web::Data::new(settings);
+        let user = |id: ObjectId| User { id, email: "ops@example.com".into(), session_id: ObjectId::new() };
+        let req = TestRequest::default().app_data(settings.clone()).to_http_request();
+        req.extensions_mut().insert(user(admin_id));
+        assert!(authenticate(&req).is_ok());
+        // A newly registered account gets a new id, whatever email it claims
+        let req = TestRequest::default().app_data(settings).to_http_request();
+        req.extensions_mut().insert(user(ObjectId::new()));
+        assert_eq!(authenticate(&req).err(), Some(AuthError::Disabled));
+    }
+
+    #[test]
+    fn test_link_manager() {
+        assert!(LinkManager::Operator.owns(None));
+        assert!(LinkManager::Operator.owns(Some("team-a")));
+        let user_id = ObjectId::new();
+        let user = LinkManager::User(user_id);
+        assert!(user.owns(Some(&user_id.to_hex())));
+        assert!(!user.owns(Some(&ObjectId::new().to_hex())));
+        assert!(!user.owns(None));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+    }
+}
@@ -0,0 +1,407 @@
+//! Batch Module
+//!
+//! `POST /api/shorten/batch` shortens up to `[links] max_batch_size` URLs in
+//! one call. Each URL goes through the same validation, UTM tagging and
+//! normalization as `POST /api/shorten` and may ask for its own alias.
+//! Destinations that already have a matching link reuse it, like single
+//! shortening does; the rest are inserted with unordered bulk writes.
+//!
+//! Items fail on their own: the response lists a result per item, in
+//! request order, and the batch as a whole only fails for problems shared by
+//! every item (authentication, an unknown domain or workspace).
+
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, DateTime as MongoDateTime};
+use mongodb::error::ErrorKind;
+use mongodb::options::InsertManyOptions;
+use mongodb::{Client, Collection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use thiserror::Error;
+
+use crate::accounts::User;
+use crate::api_keys::{self, ApiKey, Scope};
+use crate::bloom_filter::CodeFilter;
+use crate::config::Config;
+use crate::domains::Domains;
+use crate::links::{rfc3339, LinkStatus};
+use crate::passthrough::Passthrough;
+use crate::public_url::PublicUrl;
+use crate::redirect_cache::RedirectCache;
+use crate::redirect_type::RedirectType;
+use crate::utm::UtmParams;
+use crate::url_service::UrlService;
+use crate::{admin, listing, workspaces, UrlDoc};
+
+const MIN_ALIAS_LENGTH: usize = 3;
+const MAX_ALIAS_LENGTH: usize = 32;
+/// First path segments the server routes itself
+pub const RESERVED_ALIASES: &[&str] = &["api", "health", "db_health", "metrics"];
+/// Rounds of fresh codes for random codes that collided
+const MAX_ATTEMPTS: usize = 5;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AliasError {
+    #[error("Aliases must be {MIN_ALIAS_LENGTH} to {MAX_ALIAS_LENGTH} of A-Z, a-z, 0-9, - and _")]
+    Invalid,
+    #[error("Alias {0:?} is reserved")]
+    Reserved(String),
+}
+
+pub fn validate_alias(alias: &str) -> Result<(), AliasError> {
+    let valid = (MIN_ALIAS_LENGTH..=MAX_ALIAS_LENGTH).contains(&alias.len())
+        && alias.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if !valid {
+        return Err(AliasError::Invalid);
+    }
+    if RESERVED_ALIASES.iter().any(|reserved| reserved.eq_ignore_ascii_case(alias)) {
+        return Err(AliasError::Reserved(alias.to_string()));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct BatchItem {
+    url: String,
+    /// Custom short code instead of a random one
+    #[serde(default)]
+    alias: Option<String>,
+}
+
+/// Options shared by every item, as in `POST /api/shorten`
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    urls: Vec<BatchItem>,
+    #[serde(default)]
+    domain: Option<String>,
+    #[serde(default)]
+    redirect_type: Option<RedirectType>,
+    #[serde(default)]
+    passthrough: Option<Passthrough>,
+    #[serde(default)]
+    utm: UtmParams,
+    #[serde(default)]
+    workspace_id: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemStatus {
+    Created,
+    /// An existing link with the same destination and options was returned
+    Existing,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ItemResult {
+    index: usize,
+    /// As sent
+    url: String,
+    status: ItemStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    short_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    short_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl ItemResult {
+    fn failed(index: usize, url: &str, error: impl Into<String>) -> Self {
+        ItemResult {
+            index,
+            url: url.to_string(),
+            status: ItemStatus::Failed,
+            short_code: None,
+            short_url: None,
+            original_url: None,
+            created_at: None,
+            error: Some(error.into()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BatchResponse {
+    created: usize,
+    existing: usize,
+    failed: usize,
+    results: Vec<ItemResult>,
+}
+
+impl BatchResponse {
+    fn new(results: Vec<ItemResult>) -> Self {
+        let count = |status| results.iter().filter(|result| result.status == status).count();
+        BatchResponse {
+            created: count(ItemStatus::Created),
+            existing: count(ItemStatus::Existing),
+            failed: count(ItemStatus::Failed),
+            results,
+        }
+    }
+}
+
+/// A link waiting to be inserted, with the items it answers (several when a batch repeats a destination)
+struct Pending {
+    doc: UrlDoc,
+    items: Vec<usize>,
+    alias: bool,
+}
+
+/// `POST /api/shorten/batch`
+#[allow(clippy::too_many_arguments)]
+pub async fn shorten_batch(
+    client: web::Data<Client>,
+    settings: web::Data<Config>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    url_service: web::Data<UrlService>,
+    cache: web::Data<RedirectCache>,
+    code_filter: web::Data<CodeFilter>,
+    api_key: Option<web::ReqData<ApiKey>>,
+    user: Option<web::ReqData<User>>,
+    req: web::Json<BatchRequest>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse> {
+    let api_key = api_keys::authorize(api_key.as_deref(), Scope::Write, settings.auth.require_api_key && user.is_none())?;
+    let req = req.into_inner();
+    if req.urls.is_empty() || req.urls.len() > settings.links.max_batch_size {
+        return Ok(HttpResponse::BadRequest()
+            .body(format!("A batch holds 1 to {} URLs", settings.links.max_batch_size)));
+    }
+    let workspace_id = match &req.workspace_id {
+        Some(id) => {
+            Some(workspaces::authorize_shorten(&client, id, user.as_deref(), api_key.map(|key| key.owner_id.as_str())).await?)
+        }
+        None => None,
+    };
+    let owner_id = workspace_id.map(|id| id.to_hex()).or_else(|| crate::caller_owner(user.as_deref(), api_key));
+    let domain = match domains.by_name(req.domain.as_deref()) {
+        Ok(domain) => domain,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+    let tags = listing::normalize_tags(&req.tags)?;
+    let redirect_type = req.redirect_type.unwrap_or(settings.redirects.default_type);
+    let passthrough = req.passthrough.unwrap_or(settings.redirects.default_passthrough);
+    let template = settings.utm.template_for(owner_id.as_deref());
+
+    let mut results: Vec<Option<ItemResult>> = vec![None; req.urls.len()];
+    // Items that passed validation, with their normalized destination
+    let mut valid: Vec<(usize, String)> = Vec::new();
+    let mut aliases: HashMap<&str, usize> = HashMap::new();
+    for (index, item) in req.urls.iter().enumerate() {
+        if let Some(alias) = &item.alias {
+            if let Err(e) = validate_alias(alias) {
+                results[index] = Some(ItemResult::failed(index, &item.url, e.to_string()));
+                continue;
+            }
+            if let Some(first) = aliases.insert(alias, index) {
+                aliases.insert(alias, first);
+                results[index] = Some(ItemResult::failed(index, &item.url, format!("Alias is also used by item {}", first)));
+                continue;
+            }
+        }
+        match crate::prepare_destination(&url_service, &item.url, &req.utm, template) {
+            Ok(normalized) => valid.push((index, normalized)),
+            Err(message) => results[index] = Some(ItemResult::failed(index, &item.url, message)),
+        }
+    }
+    let urls: Vec<&str> = valid.iter().map(|(_, url)| url.as_str()).collect();
+    let banned = admin::banned_destinations(&client, &urls).await?;
+    let valid: Vec<(usize, String)> = valid
+        .into_iter()
+        .zip(banned)
+        .filter_map(|((index, url), banned)| match banned {
+            Some(domain) => {
+                let error = admin::AdminError::BannedDomain(domain).to_string();
+                results[index] = Some(ItemResult::failed(index, &req.urls[index].url, error));
+                None
+            }
+            None => Some((index, url)),
+        })
+        .collect();
+    let urls: Vec<&str> = valid.iter().map(|(_, url)| url.as_str()).collect();
+    let unresolved = url_service.check_resolved(&urls).await;
+    let valid: Vec<(usize, String)> = valid
+        .into_iter()
+        .zip(unresolved)
+        .filter_map(|((index, url), error)| match error {
+            Some(e) => {
+                results[index] = Some(ItemResult::failed(index, &req.urls[index].url, e.to_string()));
+                None
+            }
+            None => Some((index, url)),
+        })
+        .collect();
+
+    let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
+    let query_error = |e: mongodb::error::Error| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e));
+    // Same matching rules as single shortening, for every destination without an alias at once
+    let reusable: Vec<&str> =
+        valid.iter().filter(|(index, _)| req.urls[*index].alias.is_none()).map(|(_, url)| url.as_str()).collect();
+    let mut existing: HashMap<String, UrlDoc> = HashMap::new();
+    if !reusable.is_empty() {
+        let filter = doc! {
+            "original_url": {"$in": reusable},
+            "domain": domain.filter_value(),
+            "redirect_type": redirect_type.filter_value(),
+            "passthrough": passthrough.filter_value(),
+            "status": null,
+            "owner_id": owner_id.as_deref(),
+            "tags": if tags.is_empty() { Bson::Null } else { Bson::from(tags.clone()) },
+        };
+        let found: Vec<UrlDoc> = collection.find(filter, None).await.map_err(query_error)?.try_collect().await.map_err(query_error)?;
+        for url_doc in found {
+            existing.entry(url_doc.original_url.clone()).or_insert(url_doc);
+        }
+    }
+
+    let now = MongoDateTime::now();
+    let mut pending: Vec<Pending> = Vec::new();
+    let mut pending_by_url: HashMap<String, usize> = HashMap::new();
+    for (index, url) in valid {
+        let alias = req.urls[index].alias.clone();
+        if alias.is_none() {
+            if let Some(url_doc) = existing.get(&url) {
+                results[index] = Some(ItemResult {
+                    index,
+                    url: req.urls[index].url.clone(),
+                    status: ItemStatus::Existing,
+                    short_code: Some(url_doc.short_code.clone()),
+                    short_url: Some(domain.short_url(&public_url, &http_req, &url_doc.short_code)),
+                    original_url: Some(url),
+                    created_at: Some(rfc3339(url_doc.created_at)),
+                    error: None,
+                });
+                continue;
+            }
+            // A destination repeated within the batch gets one link
+            if let Some(&position) = pending_by_url.get(&url) {
+                pending[position].items.push(index);
+                continue;
+            }
+            pending_by_url.insert(url.clone(), pending.len());
+        }
+        pending.push(Pending {
+            alias: alias.is_some(),
+            items: vec![index],
+            doc: UrlDoc {
+                id: None,
+                short_code: alias.unwrap_or_else(crate::generate_code),
+                domain: domain.stored_name(),
+                original_url: url,
+                created_at: now,
+                transition_count: 0,
+                redirect_type,
+                passthrough,
+                updated_at: None,
+                history: Vec::new(),
+                status: LinkStatus::Active,
+                disabled_by: None,
+                deleted_at: None,
+                created_by: api_key.map(|key| key.id),
+                owner_id: owner_id.clone(),
+                workspace_id,
+                tags: tags.clone(),
+            },
+        });
+    }
+
+    // Unordered inserts keep going past failures; random codes that collided get a fresh code and another round
+    let mut remaining: Vec<Pending> = pending;
+    let mut inserted: Vec<Pending> = Vec::new();
+    for attempt in 1..=MAX_ATTEMPTS {
+        if remaining.is_empty() {
+            break;
+        }
+        let docs: Vec<&UrlDoc> = remaining.iter().map(|p| &p.doc).collect();
+        let options = InsertManyOptions::builder().ordered(false).build();
+        let failures: HashMap<usize, i32> = match collection.insert_many(docs, options).await {
+            Ok(_) => HashMap::new(),
+            Err(e) => match *e.kind {
+                ErrorKind::BulkWrite(failure) if failure.write_concern_error.is_none() => {
+                    failure.write_errors.unwrap_or_default().into_iter().map(|e| (e.index, e.code)).collect()
+                }
+                _ => return Err(actix_web::error::ErrorInternalServerError(format!("Insert Error: {}", e))),
+            },
+        };
+        let mut retry = Vec::new();
+        for (position, mut link) in remaining.into_iter().enumerate() {
+            match failures.get(&position) {
+                None => inserted.push(link),
+                Some(11000) if !link.alias && attempt < MAX_ATTEMPTS => {
+                    link.doc.short_code = crate::generate_code();
+                    retry.push(link);
+                }
+                Some(code) => {
+                    let error = match (code, link.alias) {
+                        (11000, true) => "Alias is already taken".to_string(),
+                        (11000, false) => "Failed to generate a unique short code".to_string(),
+                        (code, _) => format!("Insert failed with code {}", code),
+                    };
+                    for &index in &link.items {
+                        results[index] = Some(ItemResult::failed(index, &req.urls[index].url, error.clone()));
+                    }
+                }
+            }
+        }
+        remaining = retry;
+    }
+
+    for link in inserted {
+        let short_code = &link.doc.short_code;
+        // The codes may have been cached as unknown before they existed
+        cache.invalidate(&domain.name, short_code);
+        code_filter.insert(domain.stored_name().as_deref(), short_code);
+        for index in link.items {
+            results[index] = Some(ItemResult {
+                index,
+                url: req.urls[index].url.clone(),
+                status: ItemStatus::Created,
+                short_code: Some(short_code.clone()),
+                short_url: Some(domain.short_url(&public_url, &http_req, short_code)),
+                original_url: Some(link.doc.original_url.clone()),
+                created_at: Some(rfc3339(now)),
+                error: None,
+            });
+        }
+    }
+    let results: Vec<ItemResult> = results.into_iter().flatten().collect();
+    tracing::info!(items = results.len(), "Shortened batch");
+    Ok(HttpResponse::Ok().json(BatchResponse::new(results)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_alias() {
+        assert!(validate_alias("spring-sale_2024").is_ok());
+        assert_eq!(validate_alias("ab"), Err(AliasError::Invalid));
+        assert_eq!(validate_alias("has space"), Err(AliasError::Invalid));
+        assert_eq!(validate_alias("préféré"), Err(AliasError::Invalid));
+        assert_eq!(validate_alias(&"a".repeat(MAX_ALIAS_LENGTH + 1)), Err(AliasError::Invalid));
+        assert_eq!(validate_alias("API"), Err(AliasError::Reserved("API".into())));
+    }
+
+    #[test]
+    fn test_response_counts() {
+        let ok = |index, status| ItemResult { status, ..ItemResult::failed(index, "https://example.com", "") };
+        let response = BatchResponse::new(vec![
+            ok(0, ItemStatus::Created),
+            ok(1, ItemStatus::Existing),
+            ItemResult::failed(2, "nope", "Invalid URL"),
+            ok(3, ItemStatus::Created),
+        ]);
+        assert_eq!((response.created, response.existing, response.failed), (2, 1, 1));
+    }
+}
@@ -0,0 +1,342 @@
+//! Bloom Filter Module
+//!
+//! In-memory Bloom filter of existing `(domain, short_code)` pairs so the
+//! redirect path can answer definitely-unknown codes with 404 without a
+//! database query. The filter is built from the `urls` collection at startup,
+//! updated on insert, and periodically topped up with links created by other
+//! instances or importers (found by `_id`, whose timestamp is the insertion
+//! time). Until the initial build completes every code is let through.
+//!
+//! With several instances, a link created on one instance is unknown to the
+//! others' filters until their next refresh, so for up to
+//! `refresh_interval_secs` they answer it with a definite 404. These 404s are
+//! not put in the redirect cache, so the link works as soon as the refresh
+//! has picked it up. Keep the interval short where links are shared right
+//! after creation.
+
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, Document};
+use mongodb::options::FindOptions;
+use mongodb::Database;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::config::BloomFilterConfig;
+
+/// How far back each refresh looks, to tolerate clock skew between instances
+const REFRESH_OVERLAP: Duration = Duration::from_secs(60);
+
+/// Fixed-size Bloom filter using double hashing
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    /// Items that set at least one new bit; repeats and the rare false positive are not counted
+    items: u64,
+    /// Items the filter was sized for
+    capacity: u64,
+}
+
+impl BloomFilter {
+    /// Size the filter for `expected_items` at the given false positive rate
+    pub fn new(expected_items: u64, false_positive_rate: f64) -> Self {
+        let n = expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(n * false_positive_rate.ln()) / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / n) * ln2).round().clamp(1.0, 16.0) as u32;
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+            items: 0,
+            capacity: expected_items.max(1),
+        }
+    }
+
+    fn indexes<T: Hash + ?Sized>(&self, item: &T) -> impl Iterator<Item = u64> {
+        let hash = |seed: u64| {
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            item.hash(&mut hasher);
+            hasher.finish()
+        };
+        let (h1, h2) = (hash(0), hash(1) | 1);
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    /// Returns whether the item was new, i.e. not already (seemingly) present
+    pub fn insert<T: Hash + ?Sized>(&mut self, item: &T) -> bool {
+        let indexes: Vec<u64> = self.indexes(item).collect();
+        let mut new = false;
+        for i in indexes {
+            let (word, bit) = ((i / 64) as usize, 1 << (i % 64));
+            new |= self.bits[word] & bit == 0;
+            self.bits[word] |= bit;
+        }
+        if new {
+            self.items += 1;
+        }
+        new
+    }
+
+    /// `false` means the item was definitely never inserted
+    pub fn contains<T: Hash + ?Sized>(&self, item: &T) -> bool {
+        self.indexes(item).all(|i| self.bits[(i / 64) as usize] & (1 << (i % 64)) != 0)
+    }
+
+    pub fn items(&self) -> u64 {
+        self.items
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+}
+
+fn key(domain: Option<&str>, short_code: &str) -> String {
+    format!("{}\0{}", domain.unwrap_or(""), short_code)
+}
+
+pub struct CodeFilter {
+    enabled: bool,
+    expected_items: u64,
+    false_positive_rate: f64,
+    refresh_interval: Duration,
+    filter: RwLock<BloomFilter>,
+    ready: AtomicBool,
+    /// Links with an `_id` at or after this one are re-read on the next refresh
+    watermark: Mutex<ObjectId>,
+    /// Codes inserted while `load` builds a new filter, added to it before it replaces the current one
+    inserted_during_load: Mutex<Option<Vec<String>>>,
+    rejections: AtomicU64,
+}
+
+impl CodeFilter {
+    pub fn new(enabled: bool, expected_items: u64, false_positive_rate: f64, refresh_interval: Duration) -> Self {
+        CodeFilter {
+            enabled,
+            expected_items,
+            false_positive_rate,
+            refresh_interval,
+            filter: RwLock::new(BloomFilter::new(if enabled { expected_items } else { 1 }, false_positive_rate)),
+            ready: AtomicBool::new(false),
+            watermark: Mutex::new(ObjectId::from_bytes([0; 12])),
+            inserted_during_load: Mutex::new(None),
+            rejections: AtomicU64::new(0),
+        }
+    }
+
+    pub fn from_config(config: &BloomFilterConfig) -> Self {
+        Self::new(
+            config.enabled,
+            config.expected_items,
+            config.false_positive_rate,
+            Duration::from_secs(config.refresh_interval_secs),
+        )
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// `false` only when the code definitely does not exist on `domain` (stored domain name)
+    pub fn might_exist(&self, domain: Option<&str>, short_code: &str) -> bool {
+        if !self.enabled || !self.ready.load(Ordering::Acquire) {
+            return true;
+        }
+        let exists = self.filter.read().unwrap().contains(&key(domain, short_code));
+        if !exists {
+            self.rejections.fetch_add(1, Ordering::Relaxed);
+        }
+        exists
+    }
+
+    /// Record a newly created code
+    pub fn insert(&self, domain: Option<&str>, short_code: &str) {
+        if self.enabled {
+            let key = key(domain, short_code);
+            // Both locks are taken in the same order as in `load`, so a code is either replayed or already scanned
+            let mut filter = self.filter.write().unwrap();
+            filter.insert(&key);
+            if let Some(inserted) = self.inserted_during_load.lock().unwrap().as_mut() {
+                inserted.push(key);
+            }
+        }
+    }
+
+    fn watermark_before(time: SystemTime) -> ObjectId {
+        let secs = time
+            .checked_sub(REFRESH_OVERLAP)
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut bytes = [0u8; 12];
+        bytes[..4].copy_from_slice(&(secs as u32).to_be_bytes());
+        ObjectId::from_bytes(bytes)
+    }
+
+    /// Add every link with `_id >= since` to `filter`; returns the number of links read
+    async fn scan(db: &Database, since: ObjectId, filter: &RwLock<BloomFilter>) -> Result<u64, mongodb::error::Error> {
+        let options = FindOptions::builder().projection(doc! {"_id": 0, "domain": 1, "short_code": 1}).build();
+        let mut cursor = db.collection::<Document>("urls").find(doc! {"_id": {"$gte": since}}, options).await?;
+        let mut count = 0;
+        while let Some(link) = cursor.try_next().await? {
+            if let Ok(short_code) = link.get_str("short_code") {
+                filter.write().unwrap().insert(&key(link.get_str("domain").ok(), short_code));
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Build a fresh filter from the whole collection and start answering from it
+    pub async fn load(&self, db: &Database) -> Result<u64, mongodb::error::Error> {
+        let started = SystemTime::now();
+        *self.inserted_during_load.lock().unwrap() = Some(Vec::new());
+        let scanned = async {
+            let total = db.collection::<Document>("urls").estimated_document_count(None).await?;
+            let fresh = RwLock::new(BloomFilter::new(self.expected_items.max(total * 2), self.false_positive_rate));
+            let count = Self::scan(db, ObjectId::from_bytes([0; 12]), &fresh).await?;
+            Ok::<_, mongodb::error::Error>((fresh.into_inner().unwrap(), count))
+        }
+        .await;
+        let mut filter = self.filter.write().unwrap();
+        let inserted = self.inserted_during_load.lock().unwrap().take().unwrap_or_default();
+        let (mut fresh, count) = scanned?;
+        for key in &inserted {
+            fresh.insert(key);
+        }
+        *filter = fresh;
+        drop(filter);
+        *self.watermark.lock().unwrap() = Self::watermark_before(started);
+        self.ready.store(true, Ordering::Release);
+        Ok(count)
+    }
+
+    /// Pick up links inserted elsewhere since the last load or refresh
+    pub async fn refresh(&self, db: &Database) -> Result<u64, mongodb::error::Error> {
+        let started = SystemTime::now();
+        let since = *self.watermark.lock().unwrap();
+        let count = Self::scan(db, since, &self.filter).await?;
+        *self.watermark.lock().unwrap() = Self::watermark_before(started);
+        // Compare with the size chosen at load, which may exceed the configured one
+        let (items, capacity) = {
+            let filter = self.filter.read().unwrap();
+            (filter.items(), filter.capacity())
+        };
+        if items > capacity {
+            tracing::warn!(items, capacity, "Bloom filter holds more codes than it was sized for");
+        }
+        Ok(count)
+    }
+
+    /// Build the filter in the background, then keep refreshing it
+    pub fn spawn_loader(self: &Arc<Self>, db: Database) {
+        let filter = self.clone();
+        tokio::spawn(async move {
+            while let Err(e) = filter.load(&db).await {
+                tracing::warn!("Failed to build short code Bloom filter, retrying: {}", e);
+                tokio::time::sleep(filter.refresh_interval).await;
+            }
+            tracing::info!(items = filter.filter.read().unwrap().items(), "Short code Bloom filter ready");
+            loop {
+                tokio::time::sleep(filter.refresh_interval).await;
+                if let Err(e) = filter.refresh(&db).await {
+                    tracing::warn!("Failed to refresh short code Bloom filter: {}", e);
+                }
+            }
+        });
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    pub fn items(&self) -> u64 {
+        self.filter.read().unwrap().items()
+    }
+
+    pub fn rejections(&self) -> u64 {
+        self.rejections.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            filter.insert(&format!("code{}", i));
+        }
+        assert!((0..1000).all(|i| filter.contains(&format!("code{}", i))));
+        assert_eq!(filter.items(), 1000);
+    }
+
+    #[test]
+    fn test_repeated_inserts_are_counted_once() {
+        let mut filter = BloomFilter::new(100, 0.01);
+        assert!(filter.insert("abc"));
+        for _ in 0..12 {
+            assert!(!filter.insert("abc"));
+        }
+        assert_eq!(filter.items(), 1);
+        assert_eq!(filter.capacity(), 100);
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let mut filter = BloomFilter::new(10000, 0.01);
+        for i in 0..10000 {
+            filter.insert(&format!("code{}", i));
+        }
+        let false_positives = (0..10000).filter(|i| filter.contains(&format!("other{}", i))).count();
+        assert!(false_positives < 300, "false positives: {}", false_positives);
+    }
+
+    #[test]
+    fn test_code_filter_lets_everything_through_until_ready() {
+        let filter = CodeFilter::new(true, 100, 0.01, Duration::from_secs(5));
+        assert!(filter.might_exist(None, "unknown"));
+        filter.ready.store(true, Ordering::Release);
+        assert!(!filter.might_exist(None, "unknown"));
+        filter.insert(None, "abc1234");
+        assert!(filter.might_exist(None, "abc1234"));
+        // Codes are namespaced per domain
+        assert!(!filter.might_exist(Some("brand-b.link"), "abc1234"));
+        assert_eq!(filter.rejections(), 2);
+    }
+
+    #[test]
+    fn test_inserts_during_load_are_replayed() {
+        let filter = CodeFilter::new(true, 100, 0.01, Duration::from_secs(5));
+        *filter.inserted_during_load.lock().unwrap() = Some(Vec::new());
+        filter.insert(None, "abc1234");
+        assert_eq!(filter.inserted_during_load.lock().unwrap().as_deref(), Some(&[key(None, "abc1234")][..]));
+        // Outside a load nothing is recorded
+        *filter.inserted_during_load.lock().unwrap() = None;
+        filter.insert(None, "def5678");
+        assert!(filter.inserted_during_load.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_disabled_code_filter() {
+        let filter = CodeFilter::new(false, 100, 0.01, Duration::from_secs(5));
+        filter.ready.store(true, Ordering::Release);
+        assert!(filter.might_exist(None, "unknown"));
+    }
+
+    #[test]
+    fn test_watermark_is_behind_now() {
+        let now = SystemTime::now();
+        let watermark = CodeFilter::watermark_before(now);
+        let secs = now.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(watermark.timestamp().timestamp_millis() / 1000, (secs - REFRESH_OVERLAP.as_secs()) as i64);
+    }
+}
@@ -0,0 +1,124 @@
+//! Command Line Module
+//!
+//! Parses the backend's command line. Without a subcommand the HTTP server is
+//! started, which keeps the container entrypoint unchanged.
+
+use std::path::PathBuf;
+
+use crate::importers::Format;
+
+pub const USAGE: &str = "Usage: backend [--config <file>] [serve | config print | import <format> <file> [--dry-run] [--domain <name>] [--owner <id>]]
+Import formats: csv, yourls-sql, yourls-csv, bitly-csv";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Run the HTTP server (default)
+    Serve,
+    /// Print the effective configuration with secrets redacted
+    ConfigPrint,
+    /// Import links exported from this or another shortener
+    Import(ImportArgs),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ImportArgs {
+    pub format: Format,
+    pub path: PathBuf,
+    /// Only report what would be imported
+    pub dry_run: bool,
+    pub domain: Option<String>,
+    pub owner_id: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Cli {
+    pub config_path: Option<PathBuf>,
+    pub command: Command,
+}
+
+impl Cli {
+    /// Parse arguments, excluding the program name
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut config_path = None;
+        let (mut dry_run, mut domain, mut owner_id) = (false, None, None);
+        let mut words = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--config" || arg == "-c" {
+                let path = args.next().ok_or_else(|| format!("{} requires a file path", arg))?;
+                config_path = Some(PathBuf::from(path));
+            } else if let Some(path) = arg.strip_prefix("--config=") {
+                config_path = Some(PathBuf::from(path));
+            } else if arg == "--dry-run" {
+                dry_run = true;
+            } else if arg == "--domain" {
+                domain = Some(args.next().ok_or_else(|| format!("{} requires a domain name", arg))?);
+            } else if arg == "--owner" {
+                owner_id = Some(args.next().ok_or_else(|| format!("{} requires a user or workspace id", arg))?);
+            } else if arg.starts_with('-') {
+                return Err(format!("Unknown option: {}", arg));
+            } else {
+                words.push(arg);
+            }
+        }
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        let command = match words.as_slice() {
+            [] | ["serve"] => Command::Serve,
+            ["config", "print"] => Command::ConfigPrint,
+            ["import", format, path] => {
+                let format = format.parse()?;
+                return Ok(Cli { config_path, command: Command::Import(ImportArgs { format, path: PathBuf::from(path), dry_run, domain, owner_id }) });
+            }
+            _ => return Err(format!("Unknown command: {}", words.join(" "))),
+        };
+        if dry_run || domain.is_some() || owner_id.is_some() {
+            return Err("--dry-run, --domain and --owner only apply to import".to_string());
+        }
+        Ok(Cli { config_path, command })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, String> {
+        Cli::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_default_command_is_serve() {
+        assert_eq!(parse(&[]).unwrap(), Cli { config_path: None, command: Command::Serve });
+    }
+
+    #[test]
+    fn test_config_print_with_file() {
+        let cli = parse(&["--config", "prod.toml", "config", "print"]).unwrap();
+        assert_eq!(cli.command, Command::ConfigPrint);
+        assert_eq!(cli.config_path, Some(PathBuf::from("prod.toml")));
+        assert_eq!(parse(&["config", "print", "--config=a.toml"]).unwrap().config_path, Some(PathBuf::from("a.toml")));
+    }
+
+    #[test]
+    fn test_import() {
+        let cli = parse(&["import", "yourls-sql", "dump.sql", "--dry-run", "--domain", "go.example.com"]).unwrap();
+        let expected = ImportArgs {
+            format: Format::YourlsSql,
+            path: PathBuf::from("dump.sql"),
+            dry_run: true,
+            domain: Some("go.example.com".to_string()),
+            owner_id: None,
+        };
+        assert_eq!(cli.command, Command::Import(expected));
+        assert!(parse(&["import", "bitly", "links.csv"]).is_err());
+        assert!(parse(&["import", "bitly-csv"]).is_err());
+        assert!(parse(&["serve", "--dry-run"]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        assert!(parse(&["config"]).is_err());
+        assert!(parse(&["--config"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+//! Click Counting Module
+//!
+//! Counts redirects either directly (one `$inc` per redirect) or write-behind:
+//! increments are aggregated in memory per link and flushed to MongoDB in a
+//! single `update` command every `flush_interval_ms` or once
+//! `flush_max_events` clicks are pending, whichever comes first.
+//!
+//! Failed flushes are merged back into the buffer and retried with the next
+//! flush. Single updates reported in a reply's `writeErrors` are retried too,
+//! but a link whose update fails `MAX_UPDATE_ATTEMPTS` times in a row (say, a
+//! non-numeric `transition_count`) has its clicks dropped and logged instead.
+//! On shutdown the buffer is drained as a shutdown hook; anything that still
+//! cannot be written is logged with its counts so it can be replayed by hand.
+
+use mongodb::bson::{doc, oid::ObjectId, Document};
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::config::ClickConfig;
+
+/// Updates per `update` command; keeps each command well below the 16 MB document limit
+const FLUSH_CHUNK_SIZE: usize = 1000;
+/// Pause between drain attempts while the database is unavailable during shutdown
+const DRAIN_RETRY_DELAY: Duration = Duration::from_millis(200);
+/// Consecutive `writeErrors` after which a link's clicks are dropped rather than retried
+const MAX_UPDATE_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClickMode {
+    /// Write every click as it happens
+    Direct,
+    /// Aggregate clicks in memory and flush them in batches
+    Buffered,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClickStats {
+    pub pending_links: usize,
+    pub pending_clicks: u64,
+    pub flushed_clicks: u64,
+    pub failed_flushes: u64,
+    pub dropped_clicks: u64,
+}
+
+pub struct ClickBuffer {
+    mode: ClickMode,
+    flush_interval: Duration,
+    flush_max_events: u64,
+    pending: Mutex<HashMap<ObjectId, i64>>,
+    pending_clicks: AtomicU64,
+    flushed_clicks: AtomicU64,
+    failed_flushes: AtomicU64,
+    dropped_clicks: AtomicU64,
+    /// Consecutive failed updates per link, reset once an update succeeds
+    update_failures: Mutex<HashMap<ObjectId, u32>>,
+    flush_now: Notify,
+}
+
+/// Indexes and messages of the updates an `update` command reply reports in `writeErrors`
+fn failed_updates(reply: &Document) -> HashMap<usize, String> {
+    reply
+        .get_array("writeErrors")
+        .map(|errors| {
+            errors
+                .iter()
+                .filter_map(|error| {
+                    let error = error.as_document()?;
+                    let index = error.get("index")?;
+                    let index = index.as_i32().map(i64::from).or_else(|| index.as_i64())?;
+                    Some((index as usize, error.get_str("errmsg").unwrap_or_default().to_string()))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl ClickBuffer {
+    pub fn new(mode: ClickMode, flush_interval: Duration, flush_max_events: u64) -> Self {
+        ClickBuffer {
+            mode,
+            flush_interval,
+            flush_max_events: flush_max_events.max(1),
+            pending: Mutex::new(HashMap::new()),
+            pending_clicks: AtomicU64::new(0),
+            flushed_clicks: AtomicU64::new(0),
+            failed_flushes: AtomicU64::new(0),
+            dropped_clicks: AtomicU64::new(0),
+            update_failures: Mutex::new(HashMap::new()),
+            flush_now: Notify::new(),
+        }
+    }
+
+    pub fn from_config(config: &ClickConfig) -> Self {
+        Self::new(config.mode, Duration::from_millis(config.flush_interval_ms), config.flush_max_events)
+    }
+
+    pub fn is_buffered(&self) -> bool {
+        self.mode == ClickMode::Buffered
+    }
+
+    /// Count one click on `url_id`
+    pub async fn record(&self, db: &Database, url_id: ObjectId) {
+        if !self.is_buffered() {
+            db.collection::<Document>("urls")
+                .update_one(doc! {"_id": url_id}, doc! {"$inc": {"transition_count": 1}}, None)
+                .await
+                .ok();
+            return;
+        }
+        self.add(url_id, 1);
+    }
+
+    fn add(&self, url_id: ObjectId, clicks: i64) {
+        if self.merge(url_id, clicks) >= self.flush_max_events {
+            self.flush_now.notify_one();
+        }
+    }
+
+    /// Add clicks without waking the flusher, returning how many are now pending
+    fn merge(&self, url_id: ObjectId, clicks: i64) -> u64 {
+        // The counter changes under the map's lock, so a concurrent `take` cannot subtract clicks it has not seen added
+        let mut map = self.pending.lock().unwrap();
+        *map.entry(url_id).or_insert(0) += clicks;
+        self.pending_clicks.fetch_add(clicks as u64, Ordering::Relaxed) + clicks as u64
+    }
+
+    /// Take everything pending, leaving the buffer empty
+    fn take(&self) -> Vec<(ObjectId, i64)> {
+        let mut map = self.pending.lock().unwrap();
+        let batch: Vec<_> = map.drain().collect();
+        let clicks: i64 = batch.iter().map(|(_, n)| n).sum();
+        self.pending_clicks.fetch_sub(clicks as u64, Ordering::Relaxed);
+        batch
+    }
+
+    /// Put a batch that could not be written back into the buffer, for the next scheduled flush
+    fn restore(&self, batch: &[(ObjectId, i64)]) {
+        for (url_id, clicks) in batch {
+            self.merge(*url_id, *clicks);
+        }
+    }
+
+    /// Count the clicks a chunk wrote and restore its failed updates, dropping
+    /// those of links that failed `MAX_UPDATE_ATTEMPTS` times in a row
+    fn settle(&self, chunk: &[(ObjectId, i64)], failed: &HashMap<usize, String>) -> u64 {
+        if !failed.is_empty() {
+            self.failed_flushes.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(failed = failed.len(), "Some click count updates failed");
+        }
+        let mut failures = self.update_failures.lock().unwrap();
+        let mut written = 0u64;
+        for (index, (url_id, clicks)) in chunk.iter().enumerate() {
+            let Some(error) = failed.get(&index) else {
+                failures.remove(url_id);
+                written += *clicks as u64;
+                continue;
+            };
+            let attempts = failures.entry(*url_id).or_insert(0);
+            *attempts += 1;
+            if *attempts >= MAX_UPDATE_ATTEMPTS {
+                failures.remove(url_id);
+                self.dropped_clicks.fetch_add(*clicks as u64, Ordering::Relaxed);
+                tracing::error!(url_id = %url_id, clicks, error = %error, "Dropping click count that keeps failing to update");
+            } else {
+                self.merge(*url_id, *clicks);
+            }
+        }
+        written
+    }
+
+    /// Write all pending clicks; on failure they are kept for the next attempt
+    pub async fn flush(&self, db: &Database) -> Result<u64, mongodb::error::Error> {
+        let batch = self.take();
+        let mut flushed = 0u64;
+        for (i, chunk) in batch.chunks(FLUSH_CHUNK_SIZE).enumerate() {
+            let updates: Vec<Document> = chunk
+                .iter()
+                .map(|(url_id, clicks)| doc! {"q": {"_id": url_id}, "u": {"$inc": {"transition_count": clicks}}})
+                .collect();
+            let command = doc! {"update": "urls", "updates": updates, "ordered": false};
+            let reply = match db.run_command(command, None).await {
+                Ok(reply) => reply,
+                Err(e) => {
+                    self.failed_flushes.fetch_add(1, Ordering::Relaxed);
+                    self.restore(&batch[i * FLUSH_CHUNK_SIZE..]);
+                    return Err(e);
+                }
+            };
+            // The command succeeds even when single updates fail
+            flushed += self.settle(chunk, &failed_updates(&reply));
+        }
+        self.flushed_clicks.fetch_add(flushed, Ordering::Relaxed);
+        Ok(flushed)
+    }
+
+    /// Background loop flushing on the interval or when the event threshold is reached
+    pub fn spawn_flusher(self: &Arc<Self>, db: Database) {
+        let buffer = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(buffer.flush_interval) => {}
+                    _ = buffer.flush_now.notified() => {}
+                }
+                if let Err(e) = buffer.flush(&db).await {
+                    tracing::warn!("Failed to flush click counts, will retry: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Flush until the buffer is empty, retrying while the database is unavailable.
+    /// Meant to run as a shutdown hook, which bounds it by the shutdown deadline.
+    pub async fn drain(&self, db: &Database) {
+        while self.pending_clicks.load(Ordering::Relaxed) > 0 {
+            match self.flush(db).await {
+                Ok(flushed) => tracing::info!(flushed, "Flushed buffered click counts"),
+                Err(e) => tracing::warn!("Failed to flush click counts during shutdown, retrying: {}", e),
+            }
+            // Clicks left after a pass are failed updates or new clicks; wait before the next one
+            if self.pending_clicks.load(Ordering::Relaxed) > 0 {
+                tokio::time::sleep(DRAIN_RETRY_DELAY).await;
+            }
+        }
+    }
+
+    /// Log clicks that were never written so they can be replayed manually
+    pub fn log_unflushed(&self) {
+        let pending = self.pending.lock().unwrap();
+        for (url_id, clicks) in pending.iter() {
+            tracing::error!(url_id = %url_id, clicks, "Click count lost on shutdown");
+        }
+    }
+
+    pub fn stats(&self) -> ClickStats {
+        ClickStats {
+            pending_links: self.pending.lock().unwrap().len(),
+            pending_clicks: self.pending_clicks.load(Ordering::Relaxed),
+            flushed_clicks: self.flushed_clicks.load(Ordering::Relaxed),
+            failed_flushes: self.failed_flushes.load(Ordering::Relaxed),
+            dropped_clicks: self.dropped_clicks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(max_events: u64) -> ClickBuffer {
+        ClickBuffer::new(ClickMode::Buffered, Duration::from_secs(1), max_events)
+    }
+
+    #[test]
+    fn test_clicks_aggregate_per_link() {
+        let buffer = buffer(100);
+        let (a, b) = (ObjectId::new(), ObjectId::new());
+        buffer.add(a, 1);
+        buffer.add(a, 1);
+        buffer.add(b, 1);
+        let stats = buffer.stats();
+        assert_eq!((stats.pending_links, stats.pending_clicks), (2, 3));
+        let mut batch = buffer.take();
+        batch.sort();
+        let mut expected = vec![(a, 2), (b, 1)];
+        expected.sort();
+        assert_eq!(batch, expected);
+        assert_eq!(buffer.stats().pending_clicks, 0);
+    }
+
+    #[test]
+    fn test_restore_merges_with_new_clicks() {
+        let buffer = buffer(100);
+        let a = ObjectId::new();
+        buffer.add(a, 1);
+        let batch = buffer.take();
+        buffer.add(a, 1);
+        buffer.restore(&batch);
+        assert_eq!(buffer.take(), vec![(a, 2)]);
+    }
+
+    #[test]
+    fn test_counter_matches_buffer_under_concurrent_takes() {
+        let buffer = Arc::new(buffer(u64::MAX));
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let buffer = buffer.clone();
+                std::thread::spawn(move || (0..10_000).for_each(|_| buffer.add(ObjectId::new(), 1)))
+            })
+            .collect();
+        let mut taken = 0;
+        while writers.iter().any(|writer| !writer.is_finished()) {
+            taken += buffer.take().iter().map(|(_, n)| n).sum::<i64>();
+            assert!(buffer.stats().pending_clicks <= 40_000);
+        }
+        writers.into_iter().for_each(|writer| writer.join().unwrap());
+        let stats = buffer.stats();
+        assert_eq!(stats.pending_clicks as i64 + taken, 40_000);
+        assert_eq!(stats.pending_links as u64, stats.pending_clicks);
+    }
+
+    #[test]
+    fn test_failed_updates() {
+        let reply = doc! {"n": 1, "ok": 1, "writeErrors": [{"index": 0, "code": 121, "errmsg": "Document failed validation"}, {"index": 2_i64, "code": 121}]};
+        assert_eq!(failed_updates(&reply), HashMap::from([(0, "Document failed validation".into()), (2, String::new())]));
+        assert!(failed_updates(&doc! {"n": 3, "ok": 1}).is_empty());
+    }
+
+    #[test]
+    fn test_update_failing_every_time_is_dropped() {
+        let buffer = buffer(100);
+        let a = ObjectId::new();
+        buffer.add(a, 5);
+        let failed = HashMap::from([(0, "Cannot apply $inc to a value of non-numeric type".to_string())]);
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let batch = buffer.take();
+            assert_eq!(batch, vec![(a, 5)]);
+            assert_eq!(buffer.settle(&batch, &failed), 0);
+        }
+        let stats = buffer.stats();
+        assert_eq!((stats.pending_clicks, stats.dropped_clicks), (0, 5));
+        assert!(buffer.update_failures.lock().unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_restore_does_not_wake_flusher() {
+        let buffer = buffer(1);
+        buffer.restore(&[(ObjectId::new(), 3)]);
+        assert!(tokio::time::timeout(Duration::from_millis(50), buffer.flush_now.notified()).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_threshold_triggers_flush() {
+        let buffer = buffer(2);
+        buffer.add(ObjectId::new(), 1);
+        buffer.add(ObjectId::new(), 1);
+        // notify_one stores a permit, so the flusher wakes even if it was not waiting yet
+        tokio::time::timeout(Duration::from_millis(100), buffer.flush_now.notified())
+            .await
+            .expect("threshold should wake the flusher");
+    }
+}
@@ -0,0 +1,214 @@
+//! CORS Policy Module
+//!
+//! Turns the `[cors]` configuration into an `actix_cors::Cors` middleware.
+//! Origins may be exact (`https://app.example.com`), wildcard subdomain
+//! patterns (`https://*.example.com`) or `*` for any origin. The policy is
+//! parsed once at startup and shared by all workers.
+
+use actix_cors::Cors;
+use actix_web::http::{header::HeaderName, Method};
+use std::sync::Arc;
+use thiserror::Error;
+use url::Url;
+
+use crate::config::CorsConfig;
+
+#[derive(Debug, Clone, PartialEq)]
+enum OriginPattern {
+    Any,
+    /// Serialized origin, e.g. `https://app.example.com`
+    Exact(String),
+    /// `scheme://*.suffix[:port]`, matching any subdomain of `suffix` (not `suffix` itself)
+    Subdomain { scheme: String, suffix: String, port: Option<u16> },
+}
+
+impl OriginPattern {
+    fn parse(raw: &str) -> Result<Self, CorsError> {
+        let raw = raw.trim();
+        if raw == "*" {
+            return Ok(OriginPattern::Any);
+        }
+        let invalid = || CorsError::InvalidOrigin(raw.into());
+        if let Some((scheme, rest)) = raw.split_once("://*.") {
+            // Parse with a placeholder label so the url crate validates the remainder
+            let parsed = Url::parse(&format!("{}://x.{}", scheme, rest)).map_err(|_| invalid())?;
+            if parsed.path() != "/" || parsed.query().is_some() {
+                return Err(invalid());
+            }
+            let host = parsed.host_str().ok_or_else(invalid)?;
+            return Ok(OriginPattern::Subdomain {
+                scheme: parsed.scheme().to_string(),
+                suffix: host[1..].to_string(),
+                port: parsed.port_or_known_default(),
+            });
+        }
+        let parsed = Url::parse(raw).map_err(|_| invalid())?;
+        if parsed.host_str().is_none() || parsed.path() != "/" || parsed.query().is_some() {
+            return Err(invalid());
+        }
+        Ok(OriginPattern::Exact(parsed.origin().ascii_serialization()))
+    }
+
+    fn matches(&self, origin: &Url) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(expected) => origin.origin().ascii_serialization() == *expected,
+            OriginPattern::Subdomain { scheme, suffix, port } => {
+                let host = origin.host_str().unwrap_or("");
+                origin.scheme() == scheme
+                    && origin.port_or_known_default() == *port
+                    && host.len() > suffix.len()
+                    && host.ends_with(suffix.as_str())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    origins: Arc<Vec<OriginPattern>>,
+    /// `None` allows any method
+    methods: Option<Vec<Method>>,
+    /// `None` allows any header
+    headers: Option<Vec<HeaderName>>,
+    supports_credentials: bool,
+    max_age_secs: Option<usize>,
+}
+
+impl CorsPolicy {
+    pub fn from_config(config: &CorsConfig) -> Result<Self, CorsError> {
+        let origins = config
+            .allowed_origins
+            .iter()
+            .map(|o| OriginPattern::parse(o))
+            .collect::<Result<Vec<_>, _>>()?;
+        if config.supports_credentials && origins.contains(&OriginPattern::Any) {
+            return Err(CorsError::WildcardWithCredentials);
+        }
+        let methods = if config.allowed_methods.iter().any(|m| m == "*") {
+            None
+        } else {
+            Some(
+                config
+                    .allowed_methods
+                    .iter()
+                    .map(|m| Method::from_bytes(m.trim().to_ascii_uppercase().as_bytes()).map_err(|_| CorsError::InvalidMethod(m.clone())))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )
+        };
+        let headers = if config.allowed_headers.iter().any(|h| h == "*") {
+            None
+        } else {
+            Some(
+                config
+                    .allowed_headers
+                    .iter()
+                    .map(|h| HeaderName::from_bytes(h.trim().as_bytes()).map_err(|_| CorsError::InvalidHeader(h.clone())))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )
+        };
+        Ok(CorsPolicy {
+            origins: Arc::new(origins),
+            methods,
+            headers,
+            supports_credentials: config.supports_credentials,
+            max_age_secs: config.max_age_secs,
+        })
+    }
+
+    pub fn is_origin_allowed(&self, origin: &str) -> bool {
+        match Url::parse(origin) {
+            Ok(origin) => self.origins.iter().any(|p| p.matches(&origin)),
+            Err(_) => false,
+        }
+    }
+
+    /// Build the middleware; called once per worker
+    pub fn middleware(&self) -> Cors {
+        let policy = self.clone();
+        let mut cors = Cors::default().allowed_origin_fn(move |origin, _req| {
+            origin.to_str().map(|o| policy.is_origin_allowed(o)).unwrap_or(false)
+        });
+        cors = match &self.methods {
+            None => cors.allow_any_method(),
+            Some(methods) => cors.allowed_methods(methods.clone()),
+        };
+        cors = match &self.headers {
+            None => cors.allow_any_header(),
+            Some(headers) => cors.allowed_headers(headers.clone()),
+        };
+        if self.supports_credentials {
+            cors = cors.supports_credentials();
+        }
+        cors.max_age(self.max_age_secs)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CorsError {
+    #[error("'{0}' is not a valid origin or origin pattern")]
+    InvalidOrigin(String),
+    #[error("'{0}' is not a valid HTTP method")]
+    InvalidMethod(String),
+    #[error("'{0}' is not a valid header name")]
+    InvalidHeader(String),
+    #[error("the '*' origin cannot be combined with supports_credentials")]
+    WildcardWithCredentials,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &[&str]) -> CorsPolicy {
+        let config = CorsConfig {
+            allowed_origins: origins.iter().map(|s| s.to_string()).collect(),
+            supports_credentials: false,
+            ..CorsConfig::default()
+        };
+        CorsPolicy::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn test_exact_origin() {
+        let policy = policy(&["https://links.extim.su", "http://localhost:3000"]);
+        assert!(policy.is_origin_allowed("https://links.extim.su"));
+        assert!(policy.is_origin_allowed("https://links.extim.su:443"));
+        assert!(policy.is_origin_allowed("http://localhost:3000"));
+        assert!(!policy.is_origin_allowed("http://localhost:3001"));
+        assert!(!policy.is_origin_allowed("http://links.extim.su"));
+        assert!(!policy.is_origin_allowed("null"));
+    }
+
+    #[test]
+    fn test_wildcard_subdomain() {
+        let policy = policy(&["https://*.extim.su"]);
+        assert!(policy.is_origin_allowed("https://links.extim.su"));
+        assert!(policy.is_origin_allowed("https://a.b.extim.su"));
+        assert!(!policy.is_origin_allowed("https://extim.su"));
+        assert!(!policy.is_origin_allowed("https://evilextim.su"));
+        assert!(!policy.is_origin_allowed("http://links.extim.su"));
+        assert!(!policy.is_origin_allowed("https://links.extim.su:8443"));
+    }
+
+    #[test]
+    fn test_any_origin() {
+        assert!(policy(&["*"]).is_origin_allowed("https://anything.example"));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let mut config = CorsConfig { allowed_origins: vec!["*".into()], ..CorsConfig::default() };
+        config.supports_credentials = true;
+        assert!(matches!(CorsPolicy::from_config(&config), Err(CorsError::WildcardWithCredentials)));
+
+        let config = CorsConfig { allowed_origins: vec!["links.extim.su".into()], ..CorsConfig::default() };
+        assert!(CorsPolicy::from_config(&config).is_err());
+
+        let config = CorsConfig { allowed_methods: vec!["GE T".into()], ..CorsConfig::default() };
+        assert!(CorsPolicy::from_config(&config).is_err());
+
+        let config = CorsConfig { allowed_headers: vec!["bad header".into()], ..CorsConfig::default() };
+        assert!(CorsPolicy::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! Short Domains Module
+//!
+//! One deployment can serve several branded short domains. Every link belongs
+//! to exactly one domain and short codes are only unique within it. The first
+//! configured domain is the primary one; its links are stored without a
+//! `domain` field so that links created before multi-domain support keep
+//! resolving.
+
+use actix_web::HttpRequest;
+use mongodb::bson::Bson;
+use serde::Serialize;
+use thiserror::Error;
+use url::Url;
+
+use crate::config::PublicConfig;
+use crate::public_url::PublicUrl;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Domain {
+    /// Lowercase host name, used to match requests and stored on links
+    pub name: String,
+    /// Canonical base URL for short links on this domain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    /// Whether this is the primary (default) domain
+    pub primary: bool,
+}
+
+impl Domain {
+    /// Value stored in `UrlDoc::domain` for links on this domain
+    pub fn stored_name(&self) -> Option<String> {
+        if self.primary { None } else { Some(self.name.clone()) }
+    }
+
+    /// Value to match `domain` against in Mongo filters (null also matches a missing field)
+    pub fn filter_value(&self) -> Bson {
+        if self.primary { Bson::Null } else { Bson::String(self.name.clone()) }
+    }
+
+    /// Full short URL for `short_code` on this domain
+    pub fn short_url(&self, public_url: &PublicUrl, req: &HttpRequest, short_code: &str) -> String {
+        match &self.base_url {
+            Some(base) => format!("{}/{}", base, short_code),
+            None => public_url.short_url(req, short_code),
+        }
+    }
+}
+
+pub struct Domains {
+    domains: Vec<Domain>,
+}
+
+impl Domains {
+    /// Build the registry from base URLs; the first entry becomes the primary domain
+    pub fn new(base_urls: &[&str]) -> Result<Self, DomainError> {
+        let mut domains: Vec<Domain> = Vec::new();
+        for (i, raw) in base_urls.iter().map(|s| s.trim()).filter(|s| !s.is_empty()).enumerate() {
+            let base_url = PublicUrl::parse_base_url(raw).map_err(|_| DomainError::Invalid(raw.into()))?;
+            let name = Url::parse(&base_url)
+                .ok()
+                .and_then(|u| u.host_str().map(|h| h.to_ascii_lowercase()))
+                .ok_or_else(|| DomainError::Invalid(raw.into()))?;
+            if domains.iter().any(|d| d.name == name) {
+                return Err(DomainError::Duplicate(name));
+            }
+            domains.push(Domain { name, base_url: Some(base_url), primary: i == 0 });
+        }
+        if domains.is_empty() {
+            return Ok(Self::single());
+        }
+        Ok(Domains { domains })
+    }
+
+    /// A single primary domain whose short URLs are rendered by `PublicUrl`
+    pub fn single() -> Self {
+        Domains {
+            domains: vec![Domain { name: "default".into(), base_url: None, primary: true }],
+        }
+    }
+
+    pub fn from_config(config: &PublicConfig) -> Result<Self, DomainError> {
+        Self::new(&config.domains.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+
+    pub fn all(&self) -> &[Domain] {
+        &self.domains
+    }
+
+    pub fn primary(&self) -> &Domain {
+        &self.domains[0]
+    }
+
+    /// Domain chosen explicitly by a client; `None` selects the primary domain
+    pub fn by_name(&self, name: Option<&str>) -> Result<&Domain, DomainError> {
+        match name.map(|n| n.trim().to_ascii_lowercase()) {
+            None => Ok(self.primary()),
+            Some(n) => self
+                .domains
+                .iter()
+                .find(|d| d.name == n)
+                .ok_or(DomainError::Unknown(n)),
+        }
+    }
+
+    /// Domain serving a request addressed to `host`; unmatched hosts fall back to the primary domain
+    pub fn for_host(&self, host: &str) -> &Domain {
+        let host = strip_port(host).to_ascii_lowercase();
+        self.domains.iter().find(|d| d.name == host).unwrap_or_else(|| self.primary())
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // IPv6 literal, e.g. [::1]:8080
+        return host.split_once(']').map(|(h, _)| &host[..h.len() + 1]).unwrap_or(host);
+    }
+    host.rsplit_once(':').map(|(h, _)| h).unwrap_or(host)
+}
+
+#[derive(Debug, Error)]
+pub enum DomainError {
+    #[error("Invalid short domain: {0}")]
+    Invalid(String),
+    #[error("Short domain configured twice: {0}")]
+    Duplicate(String),
+    #[error("Unknown short domain: {0}")]
+    Unknown(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domains() -> Domains {
+        Domains::new(&["https://go.brand-a.com", "https://brand-b.link/"]).unwrap()
+    }
+
+    #[test]
+    fn test_first_domain_is_primary() {
+        let domains = domains();
+        assert_eq!(domains.primary().name, "go.brand-a.com");
+        assert_eq!(domains.primary().stored_name(), None);
+        assert_eq!(domains.all()[1].stored_name().as_deref(), Some("brand-b.link"));
+        assert_eq!(domains.all()[1].base_url.as_deref(), Some("https://brand-b.link"));
+    }
+
+    #[test]
+    fn test_for_host() {
+        let domains = domains();
+        assert_eq!(domains.for_host("BRAND-B.link:443").name, "brand-b.link");
+        assert_eq!(domains.for_host("go.brand-a.com").name, "go.brand-a.com");
+        assert_eq!(domains.for_host("localhost:8080").name, "go.brand-a.com");
+        assert_eq!(domains.for_host("[::1]:8080").name, "go.brand-a.com");
+    }
+
+    #[test]
+    fn test_by_name() {
+        let domains = domains();
+        assert_eq!(domains.by_name(None).unwrap().name, "go.brand-a.com");
+        assert_eq!(domains.by_name(Some("Brand-B.link")).unwrap().name, "brand-b.link");
+        assert!(domains.by_name(Some("other.example")).is_err());
+    }
+
+    #[test]
+    fn test_invalid_configuration() {
+        assert!(Domains::new(&["brand-b.link"]).is_err());
+        assert!(Domains::new(&["https://a.link", "http://a.link"]).is_err());
+        assert_eq!(Domains::new(&[""]).unwrap().primary().name, "default");
+    }
+}
@@ -0,0 +1,454 @@
+//! Importers Module
+//!
+//! Reads the link exports of other shorteners for `backend import`: YOURLS
+//! SQL dumps (the `INSERT`s into its `yourls_url` table) and CSV exports, and
+//! Bitly CSV exports. Keywords and Bitlinks become short codes and click
+//! counts become `transition_count`; the rows then go through the same checks
+//! and insertion as the CSV import endpoint.
+
+use mongodb::Client;
+use std::str::FromStr;
+
+use crate::cli::ImportArgs;
+use crate::config::Config;
+use crate::domains::Domains;
+use crate::link_csv::{self, ImportRow, RowProblem};
+use crate::url_service::UrlService;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// This service's own `code,url,created_at,clicks,status`
+    Csv,
+    YourlsSql,
+    YourlsCsv,
+    BitlyCsv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "yourls-sql" => Ok(Format::YourlsSql),
+            "yourls-csv" => Ok(Format::YourlsCsv),
+            "bitly-csv" => Ok(Format::BitlyCsv),
+            _ => Err(format!("Unknown import format {:?}, expected csv, yourls-sql, yourls-csv or bitly-csv", s)),
+        }
+    }
+}
+
+/// Column names of the `yourls_url` table, in table order
+const YOURLS_COLUMNS: [&str; 6] = ["keyword", "url", "title", "timestamp", "ip", "clicks"];
+
+/// Accepted header names per field, lowercase with `_` read as a space
+struct Fields {
+    code: &'static [&'static str],
+    url: &'static [&'static str],
+    created_at: &'static [&'static str],
+    clicks: &'static [&'static str],
+}
+
+const YOURLS_FIELDS: Fields = Fields { code: &["keyword"], url: &["url"], created_at: &["timestamp"], clicks: &["clicks"] };
+
+const BITLY_FIELDS: Fields = Fields {
+    code: &["bitlink", "link", "short url", "short link"],
+    url: &["long url", "destination", "original url"],
+    created_at: &["created", "created at", "date created", "creation date"],
+    clicks: &["clicks", "total clicks", "engagements"],
+};
+
+/// Where each field sits in a record
+struct Columns {
+    code: usize,
+    url: usize,
+    created_at: Option<usize>,
+    clicks: Option<usize>,
+}
+
+impl Columns {
+    fn find(header: &[String], fields: &Fields) -> Option<Self> {
+        let position = |names: &[&str]| {
+            header.iter().position(|column| names.contains(&column.trim().to_ascii_lowercase().replace('_', " ").as_str()))
+        };
+        Some(Columns {
+            code: position(fields.code)?,
+            url: position(fields.url)?,
+            created_at: position(fields.created_at),
+            clicks: position(fields.clicks),
+        })
+    }
+
+    fn row(
+        &self,
+        url_service: &UrlService,
+        line: usize,
+        values: &[Option<String>],
+        code: impl Fn(&str) -> &str,
+    ) -> Result<ImportRow, RowProblem> {
+        let field = |n: Option<usize>| n.and_then(|n| values.get(n)).and_then(|v| v.as_deref()).unwrap_or("");
+        link_csv::parse_row(url_service, line, code(field(Some(self.code))), field(Some(self.url)), field(self.created_at), field(self.clicks))
+    }
+}
+
+/// Bitly exports full Bitlinks such as `bit.ly/3xYzAbc`; the code is the last segment
+fn bitlink_code(bitlink: &str) -> &str {
+    bitlink.trim().trim_end_matches('/').rsplit('/').next().unwrap_or("")
+}
+
+fn yourls_code(keyword: &str) -> &str {
+    keyword
+}
+
+/// A CSV export with a header naming its columns, or, for YOURLS, none at all
+fn parse_table(
+    url_service: &UrlService,
+    data: &[u8],
+    fields: &Fields,
+    code: fn(&str) -> &str,
+    headerless: Option<&[&str]>,
+) -> (Vec<ImportRow>, Vec<RowProblem>) {
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(data);
+    let (mut rows, mut invalid) = (Vec::new(), Vec::new());
+    let mut columns = None;
+    for (i, record) in reader.records().enumerate() {
+        let line = i + 1;
+        let record: Vec<Option<String>> = match record {
+            Ok(record) => record.iter().map(|value| Some(value.to_string())).collect(),
+            Err(e) => {
+                invalid.push(RowProblem::new(line, "", format!("Unreadable row: {}", e)));
+                continue;
+            }
+        };
+        if record.iter().flatten().all(|value| value.trim().is_empty()) {
+            continue;
+        }
+        let columns = match &columns {
+            Some(columns) => columns,
+            None => {
+                let header: Vec<String> = record.iter().flatten().cloned().collect();
+                let default = headerless.map(|names| names.iter().map(|name| name.to_string()).collect::<Vec<_>>());
+                match (Columns::find(&header, fields), default.and_then(|names| Columns::find(&names, fields))) {
+                    (Some(found), _) => {
+                        columns = Some(found);
+                        continue;
+                    }
+                    (None, Some(default)) => &*columns.insert(default),
+                    (None, None) => {
+                        invalid.push(RowProblem::new(line, "", format!("Expected a header with the columns {} and {}", fields.code[0], fields.url[0])));
+                        break;
+                    }
+                }
+            }
+        };
+        match columns.row(url_service, line, &record, code) {
+            Ok(row) => rows.push(row),
+            Err(problem) => invalid.push(problem),
+        }
+    }
+    (rows, invalid)
+}
+
+/// Just enough of MySQL's dump syntax to read `INSERT ... VALUES` statements
+struct SqlScanner<'a> {
+    sql: &'a str,
+    pos: usize,
+    /// Line number at `counted`; the position only moves forward, so lines are counted once
+    line: usize,
+    counted: usize,
+}
+
+impl<'a> SqlScanner<'a> {
+    fn new(sql: &'a str) -> Self {
+        SqlScanner { sql, pos: 0, line: 1, counted: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.sql[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_space();
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn line(&mut self) -> usize {
+        self.line += self.sql[self.counted..self.pos].matches('\n').count();
+        self.counted = self.pos;
+        self.line
+    }
+
+    /// Skip whitespace and comments; false at the end of the input
+    fn skip_space(&mut self) -> bool {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if trimmed.starts_with("--") || trimmed.starts_with('#') {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else if let Some(comment) = trimmed.strip_prefix("/*") {
+                self.pos += comment.find("*/").map_or(trimmed.len(), |end| end + 4);
+            } else {
+                return !trimmed.is_empty();
+            }
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        self.skip_space();
+        let rest = self.rest();
+        let matches = rest.get(..keyword.len()).is_some_and(|word| word.eq_ignore_ascii_case(keyword))
+            && !rest[keyword.len()..].starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_');
+        if matches {
+            self.pos += keyword.len();
+        }
+        matches
+    }
+
+    /// A bare, backquoted or double-quoted name; of `db`.`table` the last part
+    fn identifier(&mut self) -> String {
+        let mut name = String::new();
+        loop {
+            self.skip_space();
+            name.clear();
+            match self.peek() {
+                Some(quote @ ('`' | '"')) => {
+                    self.bump();
+                    while let Some(c) = self.bump() {
+                        if c == quote {
+                            break;
+                        }
+                        name.push(c);
+                    }
+                }
+                _ => {
+                    while let Some(c) = self.peek().filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '$') {
+                        name.push(c);
+                        self.bump();
+                    }
+                }
+            }
+            if self.peek() != Some('.') {
+                return name;
+            }
+            self.bump();
+        }
+    }
+
+    fn value(&mut self) -> Option<Option<String>> {
+        self.skip_space();
+        if self.peek() != Some('\'') {
+            let end = self.rest().find([',', ')']).unwrap_or(self.rest().len());
+            let token = self.rest()[..end].trim().to_string();
+            self.pos += end;
+            return match token.as_str() {
+                "" => None,
+                _ if token.eq_ignore_ascii_case("NULL") => Some(None),
+                _ => Some(Some(token)),
+            };
+        }
+        self.bump();
+        let mut value = String::new();
+        loop {
+            match self.bump()? {
+                '\\' => value.push(match self.bump()? {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    '0' => '\0',
+                    c => c,
+                }),
+                '\'' if self.peek() == Some('\'') => {
+                    self.bump();
+                    value.push('\'');
+                }
+                '\'' => return Some(Some(value)),
+                c => value.push(c),
+            }
+        }
+    }
+
+    /// `(value, ...)`
+    fn tuple(&mut self) -> Option<Vec<Option<String>>> {
+        if !self.eat('(') {
+            return None;
+        }
+        let mut values = Vec::new();
+        loop {
+            values.push(self.value()?);
+            if self.eat(')') {
+                return Some(values);
+            }
+            if !self.eat(',') {
+                return None;
+            }
+        }
+    }
+
+    /// Move past the next `;` outside string literals
+    fn skip_statement(&mut self) {
+        let mut quote = None;
+        while let Some(c) = self.bump() {
+            match (c, quote) {
+                ('\\', Some(_)) => {
+                    self.bump();
+                }
+                ('\'' | '"' | '`', None) => quote = Some(c),
+                (c, Some(q)) if c == q => quote = None,
+                (';', None) => return,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Rows of the `INSERT`s into a table named like `yourls_url`; other tables are ignored
+fn parse_yourls_sql(url_service: &UrlService, sql: &str) -> (Vec<ImportRow>, Vec<RowProblem>) {
+    let mut scanner = SqlScanner::new(sql);
+    let (mut rows, mut invalid) = (Vec::new(), Vec::new());
+    while scanner.skip_space() {
+        if !scanner.keyword("INSERT") {
+            scanner.skip_statement();
+            continue;
+        }
+        scanner.keyword("IGNORE");
+        if !scanner.keyword("INTO") {
+            scanner.skip_statement();
+            continue;
+        }
+        let table = scanner.identifier();
+        let mut header: Vec<String> = YOURLS_COLUMNS.iter().map(|name| name.to_string()).collect();
+        if scanner.eat('(') {
+            header.clear();
+            loop {
+                header.push(scanner.identifier());
+                if !scanner.eat(',') {
+                    break;
+                }
+            }
+            scanner.eat(')');
+        }
+        let columns = Columns::find(&header, &YOURLS_FIELDS);
+        let (Some(columns), true, true) = (columns, table.to_ascii_lowercase().ends_with("url"), scanner.keyword("VALUES")) else {
+            scanner.skip_statement();
+            continue;
+        };
+        loop {
+            scanner.skip_space();
+            let line = scanner.line();
+            let Some(values) = scanner.tuple() else {
+                invalid.push(RowProblem::new(line, "", "Unreadable row"));
+                break;
+            };
+            match columns.row(url_service, line, &values, yourls_code) {
+                Ok(row) => rows.push(row),
+                Err(problem) => invalid.push(problem),
+            }
+            if !scanner.eat(',') {
+                break;
+            }
+        }
+        scanner.skip_statement();
+    }
+    (rows, invalid)
+}
+
+/// Rows of an export in the given format, and the problems of the others
+pub fn parse(url_service: &UrlService, format: Format, data: &[u8]) -> (Vec<ImportRow>, Vec<RowProblem>) {
+    match format {
+        Format::Csv => link_csv::parse_csv(url_service, data),
+        Format::YourlsSql => parse_yourls_sql(url_service, &String::from_utf8_lossy(data)),
+        Format::YourlsCsv => parse_table(url_service, data, &YOURLS_FIELDS, yourls_code, Some(&YOURLS_COLUMNS)),
+        Format::BitlyCsv => parse_table(url_service, data, &BITLY_FIELDS, bitlink_code, None),
+    }
+}
+
+/// `backend import`: import a file and print the report as JSON
+pub async fn run(client: &Client, config: &Config, args: &ImportArgs) -> Result<(), String> {
+    let data = std::fs::read(&args.path).map_err(|e| format!("Cannot read {}: {}", args.path.display(), e))?;
+    let domains = Domains::from_config(&config.public).map_err(|e| e.to_string())?;
+    let domain = domains.by_name(args.domain.as_deref()).map_err(|e| e.to_string())?;
+    let url_service = UrlService::from_config(&config.links);
+    let (rows, invalid) = parse(&url_service, args.format, &data);
+    let report = link_csv::import_rows(client, config, &url_service, domain, rows, invalid, args.owner_id.clone(), args.dry_run)
+        .await
+        .map_err(|e| e.to_string())?;
+    println!("{}", serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(rows: &[ImportRow]) -> Vec<&str> {
+        rows.iter().map(|row| row.code.as_str()).collect()
+    }
+
+    #[test]
+    fn test_yourls_sql() {
+        let sql = r#"
+-- MySQL dump
+DROP TABLE IF EXISTS `yourls_url`;
+CREATE TABLE `yourls_url` (`keyword` varchar(100) NOT NULL, `url` text) ENGINE=InnoDB;
+INSERT INTO `yourls_options` VALUES (1,'version','1.9');
+/*!40000 ALTER TABLE `yourls_url` DISABLE KEYS */;
+INSERT INTO `yourls_url` VALUES ('ozh','http://ozh.org/','Ozh; \'s site','2020-01-02 03:04:05','127.0.0.1',12),
+('bad','javascript:alert(1)',NULL,'2020-01-02 03:04:05','127.0.0.1',0);
+INSERT IGNORE INTO `db`.`yourls_url` (`url`, `keyword`, `clicks`) VALUES ('https://example.com/it''s','x-1',3);
+"#;
+        let (rows, invalid) = parse(&UrlService::new_dummy(), Format::YourlsSql, sql.as_bytes());
+        assert_eq!(codes(&rows), vec!["ozh", "x-1"]);
+        assert_eq!(rows[0].url, "http://ozh.org/");
+        assert_eq!(rows[0].created_at, Some(mongodb::bson::DateTime::from_millis(1_577_934_245_000)));
+        assert_eq!((rows[0].clicks, rows[0].line), (12, 7));
+        assert_eq!((rows[1].clicks, rows[1].created_at, rows[1].line), (3, None, 9));
+        assert_eq!(rows[1].url, "https://example.com/it's");
+        assert_eq!(invalid.len(), 1);
+        assert_eq!((invalid[0].line, invalid[0].code.as_str()), (8, "bad"));
+    }
+
+    #[test]
+    fn test_yourls_csv_with_and_without_header() {
+        let with_header = b"keyword,url,title,timestamp,ip,clicks\nabc,https://example.com/a,A,2020-01-02 03:04:05,::1,4\n";
+        let (rows, invalid) = parse(&UrlService::new_dummy(), Format::YourlsCsv, with_header);
+        assert!(invalid.is_empty());
+        assert_eq!((codes(&rows), rows[0].clicks, rows[0].line), (vec!["abc"], 4, 2));
+        let (rows, _) = parse(&UrlService::new_dummy(), Format::YourlsCsv, b"abc,https://example.com/a,A,2020-01-02 03:04:05,::1,4\n");
+        assert_eq!((codes(&rows), rows[0].clicks), (vec!["abc"], 4));
+    }
+
+    #[test]
+    fn test_bitly_csv() {
+        let data = b"Title,Bitlink,Long URL,Created,Clicks\nLaunch,bit.ly/3xYzAbc,https://example.com/launch,2023-01-05T10:00:00+0000,42\n,https://bit.ly/spring-sale/,https://example.com/sale,,\n";
+        let (rows, invalid) = parse(&UrlService::new_dummy(), Format::BitlyCsv, data);
+        assert!(invalid.is_empty());
+        assert_eq!(codes(&rows), vec!["3xYzAbc", "spring-sale"]);
+        assert_eq!(rows[0].clicks, 42);
+        assert!(rows[0].created_at.is_some());
+        let (rows, invalid) = parse(&UrlService::new_dummy(), Format::BitlyCsv, b"bit.ly/abc,https://example.com\n");
+        assert!(rows.is_empty());
+        assert_eq!(invalid.len(), 1);
+    }
+
+    #[test]
+    fn test_format_names() {
+        assert_eq!("yourls-sql".parse::<Format>(), Ok(Format::YourlsSql));
+        assert!("bitly".parse::<Format>().is_err());
+    }
+}
@@ -0,0 +1,443 @@
+//! Link CSV Module
+//!
+//! Moves links in and out as CSV with the columns `code,url,created_at,clicks`
+//! and an optional fifth `status`, for migrations from other shorteners and
+//! for backups. The header row is optional on import; `created_at`
+//! (RFC 3339), `clicks` and `status` may be empty or left out, so four-column
+//! files from other tools import as they are. Disabled links
+//! are imported disabled, as if by an admin, so restoring a backup does not
+//! bring moderated links back.
+//!
+//! Imports keep the given codes. Every row is checked first: rows with an
+//! invalid code, URL, date or click count are reported as invalid, and rows
+//! whose code already exists (in the store or earlier in the file) as
+//! conflicts. A dry run stops at the report; otherwise the remaining rows are
+//! inserted. Both endpoints are for operators, under `/api/admin`.
+
+use actix_web::web::Bytes;
+use actix_web::{web, HttpResponse, Result};
+use futures::{stream, StreamExt, TryStreamExt};
+use mongodb::bson::{doc, DateTime as MongoDateTime};
+use mongodb::error::ErrorKind;
+use mongodb::options::InsertManyOptions;
+use mongodb::{Client, Collection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::auth::Authenticated;
+use crate::batch::RESERVED_ALIASES;
+use crate::bloom_filter::CodeFilter;
+use crate::config::Config;
+use crate::domains::{Domain, Domains};
+use crate::links::{rfc3339, DisabledBy, LinkStatus};
+use crate::redirect_cache::RedirectCache;
+use crate::url_service::UrlService;
+use crate::{admin, UrlDoc};
+
+/// Exports always include `status`; imports also take the first four columns alone
+pub const HEADER: [&str; 5] = ["code", "url", "created_at", "clicks", "status"];
+const MAX_CODE_LENGTH: usize = 64;
+/// Request body limit for imports, well above the default for JSON bodies
+pub const MAX_IMPORT_BYTES: usize = 16 * 1024 * 1024;
+
+/// Old shorteners used all sorts of code lengths, so only the characters are checked
+pub fn is_valid_code(code: &str) -> bool {
+    !code.is_empty() && code.len() <= MAX_CODE_LENGTH && code.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// A row ready to be stored
+#[derive(Debug, PartialEq)]
+pub struct ImportRow {
+    /// 1-based line in the file
+    pub line: usize,
+    pub code: String,
+    pub url: String,
+    pub created_at: Option<MongoDateTime>,
+    pub clicks: i64,
+    /// Active or disabled
+    pub status: LinkStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RowProblem {
+    pub line: usize,
+    pub code: String,
+    pub reason: String,
+}
+
+impl RowProblem {
+    pub fn new(line: usize, code: &str, reason: impl Into<String>) -> Self {
+        RowProblem { line, code: code.to_string(), reason: reason.into() }
+    }
+}
+
+/// Check one row's fields, normalizing the URL the way shortening does
+pub fn parse_row(
+    url_service: &UrlService,
+    line: usize,
+    code: &str,
+    url: &str,
+    created_at: &str,
+    clicks: &str,
+) -> Result<ImportRow, RowProblem> {
+    let code = code.trim();
+    if !is_valid_code(code) {
+        return Err(RowProblem::new(line, code, format!("Codes must be 1 to {} of A-Z, a-z, 0-9, - and _", MAX_CODE_LENGTH)));
+    }
+    // The server's own routes would shadow these codes
+    if RESERVED_ALIASES.iter().any(|reserved| reserved.eq_ignore_ascii_case(code)) {
+        return Err(RowProblem::new(line, code, format!("Code {:?} is reserved", code)));
+    }
+    let url = url_service
+        .validate_url(url.trim())
+        .and_then(|_| url_service.normalize_url(url.trim()))
+        .map_err(|e| RowProblem::new(line, code, e.to_string()))?;
+    let created_at = match created_at.trim() {
+        "" => None,
+        value => Some(parse_timestamp(value).ok_or_else(|| RowProblem::new(line, code, format!("Invalid created_at {:?}", value)))?),
+    };
+    let clicks = match clicks.trim() {
+        "" => 0,
+        value => value.parse::<i64>().ok().filter(|n| *n >= 0).ok_or_else(|| RowProblem::new(line, code, format!("Invalid clicks {:?}", value)))?,
+    };
+    Ok(ImportRow { line, code: code.to_string(), url, created_at, clicks, status: LinkStatus::Active })
+}
+
+/// The `status` column; deleted links are not exported, so they cannot be imported either
+pub fn parse_status(value: &str) -> Option<LinkStatus> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "" | "active" => Some(LinkStatus::Active),
+        "disabled" => Some(LinkStatus::Disabled),
+        _ => None,
+    }
+}
+
+fn status_column(status: LinkStatus) -> &'static str {
+    match status {
+        LinkStatus::Active => "active",
+        LinkStatus::Disabled => "disabled",
+        LinkStatus::Deleted => "deleted",
+    }
+}
+
+/// RFC 3339, or the zone-less `2020-01-02 03:04:05` of SQL dumps taken as UTC
+pub fn parse_timestamp(value: &str) -> Option<MongoDateTime> {
+    let date = chrono::DateTime::parse_from_rfc3339(value)
+        .or_else(|_| chrono::DateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%z"))
+        .map(|date| date.timestamp_millis())
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").map(|date| date.and_utc().timestamp_millis()))
+        .ok()?;
+    Some(MongoDateTime::from_millis(date))
+}
+
+/// Rows that look valid on their own, and the problems of the others
+pub fn parse_csv(url_service: &UrlService, data: &[u8]) -> (Vec<ImportRow>, Vec<RowProblem>) {
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(data);
+    let (mut rows, mut invalid) = (Vec::new(), Vec::new());
+    for (i, record) in reader.records().enumerate() {
+        let line = i + 1;
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                invalid.push(RowProblem::new(line, "", format!("Unreadable row: {}", e)));
+                continue;
+            }
+        };
+        let field = |n: usize| record.get(n).unwrap_or("");
+        if line == 1 && field(0).trim().eq_ignore_ascii_case(HEADER[0]) && field(1).trim().eq_ignore_ascii_case(HEADER[1]) {
+            continue;
+        }
+        if record.iter().all(|f| f.trim().is_empty()) {
+            continue;
+        }
+        if record.len() < 2 || record.len() > HEADER.len() {
+            invalid.push(RowProblem::new(line, field(0), format!("Expected the columns {}", HEADER.join(","))));
+            continue;
+        }
+        let Some(status) = parse_status(field(4)) else {
+            invalid.push(RowProblem::new(line, field(0).trim(), format!("Invalid status {:?}, expected active or disabled", field(4))));
+            continue;
+        };
+        match parse_row(url_service, line, field(0), field(1), field(2), field(3)) {
+            Ok(row) => rows.push(ImportRow { status, ..row }),
+            Err(problem) => invalid.push(problem),
+        }
+    }
+    (rows, invalid)
+}
+
+/// Split off rows whose code is taken by an existing link or by an earlier row
+pub fn split_conflicts(rows: Vec<ImportRow>, taken: &HashSet<String>) -> (Vec<ImportRow>, Vec<RowProblem>) {
+    let mut seen = HashSet::new();
+    let (mut accepted, mut conflicts) = (Vec::new(), Vec::new());
+    for row in rows {
+        if taken.contains(&row.code) {
+            conflicts.push(RowProblem::new(row.line, &row.code, "Code already exists"));
+        } else if !seen.insert(row.code.clone()) {
+            conflicts.push(RowProblem::new(row.line, &row.code, "Code appears earlier in the file"));
+        } else {
+            accepted.push(row);
+        }
+    }
+    (accepted, conflicts)
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    #[serde(default)]
+    dry_run: bool,
+    domain: Option<String>,
+    /// Owner to give the imported links; unowned by default
+    owner_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    dry_run: bool,
+    rows: usize,
+    /// Rows that would be (or were) imported
+    importable: usize,
+    imported: usize,
+    conflicts: Vec<RowProblem>,
+    invalid: Vec<RowProblem>,
+    /// Codes of the imported links
+    #[serde(skip)]
+    codes: Vec<String>,
+}
+
+/// `POST /api/admin/import`: the body is the CSV itself
+#[allow(clippy::too_many_arguments)]
+pub async fn import_links(
+    _auth: Authenticated,
+    client: web::Data<Client>,
+    settings: web::Data<Config>,
+    domains: web::Data<Domains>,
+    url_service: web::Data<UrlService>,
+    cache: web::Data<RedirectCache>,
+    code_filter: web::Data<CodeFilter>,
+    query: web::Query<ImportQuery>,
+    body: Bytes,
+) -> Result<HttpResponse> {
+    let domain = match domains.by_name(query.domain.as_deref()) {
+        Ok(domain) => domain,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+    let (rows, invalid) = parse_csv(&url_service, &body);
+    let report = import_rows(&client, &settings, &url_service, domain, rows, invalid, query.owner_id.clone(), query.dry_run).await?;
+    for code in &report.codes {
+        cache.invalidate(&domain.name, code);
+        code_filter.insert(domain.stored_name().as_deref(), code);
+    }
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Check parsed rows against banned domains, destination lookups and existing links, then insert
+/// the rest unless this is a dry run. Shared by the CSV endpoint and the
+/// command line importers.
+#[allow(clippy::too_many_arguments)]
+pub async fn import_rows(
+    client: &Client,
+    settings: &Config,
+    url_service: &UrlService,
+    domain: &Domain,
+    rows: Vec<ImportRow>,
+    mut invalid: Vec<RowProblem>,
+    owner_id: Option<String>,
+    dry_run: bool,
+) -> Result<ImportReport> {
+    let total = rows.len() + invalid.len();
+    // Banned destinations are as invalid as malformed ones
+    let urls: Vec<&str> = rows.iter().map(|row| row.url.as_str()).collect();
+    let banned = admin::banned_destinations(client, &urls).await?;
+    let rows: Vec<ImportRow> = rows
+        .into_iter()
+        .zip(banned)
+        .filter_map(|(row, banned)| match banned {
+            Some(domain) => {
+                invalid.push(RowProblem::new(row.line, &row.code, admin::AdminError::BannedDomain(domain).to_string()));
+                None
+            }
+            None => Some(row),
+        })
+        .collect();
+    let urls: Vec<&str> = rows.iter().map(|row| row.url.as_str()).collect();
+    let unresolved = url_service.check_resolved(&urls).await;
+    let rows: Vec<ImportRow> = rows
+        .into_iter()
+        .zip(unresolved)
+        .filter_map(|(row, error)| match error {
+            Some(e) => {
+                invalid.push(RowProblem::new(row.line, &row.code, e.to_string()));
+                None
+            }
+            None => Some(row),
+        })
+        .collect();
+    let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
+    let query_error = |e: mongodb::error::Error| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e));
+    let codes: Vec<&str> = rows.iter().map(|row| row.code.as_str()).collect();
+    let taken: HashSet<String> = collection
+        .find(doc! {"domain": domain.filter_value(), "short_code": {"$in": codes}}, None)
+        .await
+        .map_err(query_error)?
+        .map_ok(|url_doc| url_doc.short_code)
+        .try_collect()
+        .await
+        .map_err(query_error)?;
+    let (rows, mut conflicts) = split_conflicts(rows, &taken);
+    let importable = rows.len();
+    let mut codes = Vec::new();
+    if !dry_run && !rows.is_empty() {
+        let now = MongoDateTime::now();
+        let docs: Vec<UrlDoc> = rows
+            .iter()
+            .map(|row| UrlDoc {
+                id: None,
+                short_code: row.code.clone(),
+                domain: domain.stored_name(),
+                original_url: row.url.clone(),
+                created_at: row.created_at.unwrap_or(now),
+                transition_count: row.clicks,
+                redirect_type: settings.redirects.default_type,
+                passthrough: settings.redirects.default_passthrough,
+                updated_at: None,
+                history: Vec::new(),
+                status: row.status,
+                disabled_by: (row.status == LinkStatus::Disabled).then_some(DisabledBy::Admin),
+                deleted_at: None,
+                created_by: None,
+                owner_id: owner_id.clone(),
+                workspace_id: None,
+                tags: Vec::new(),
+            })
+            .collect();
+        let options = InsertManyOptions::builder().ordered(false).build();
+        // Codes taken since the check above surface as duplicate key errors
+        let failed: HashSet<usize> = match collection.insert_many(&docs, options).await {
+            Ok(_) => HashSet::new(),
+            Err(e) => match *e.kind {
+                ErrorKind::BulkWrite(failure) if failure.write_concern_error.is_none() => {
+                    let errors = failure.write_errors.unwrap_or_default();
+                    for error in &errors {
+                        let row = &rows[error.index];
+                        let reason = if error.code == 11000 { "Code already exists".to_string() } else { error.message.clone() };
+                        conflicts.push(RowProblem::new(row.line, &row.code, reason));
+                    }
+                    errors.into_iter().map(|error| error.index).collect()
+                }
+                _ => return Err(actix_web::error::ErrorInternalServerError(format!("Insert Error: {}", e))),
+            },
+        };
+        codes = rows.iter().enumerate().filter(|(index, _)| !failed.contains(index)).map(|(_, row)| row.code.clone()).collect();
+        tracing::info!(imported = codes.len(), conflicts = conflicts.len(), invalid = invalid.len(), "Imported links");
+    }
+    conflicts.sort_by_key(|problem| problem.line);
+    invalid.sort_by_key(|problem| problem.line);
+    Ok(ImportReport { dry_run, rows: total, importable, imported: codes.len(), conflicts, invalid, codes })
+}
+
+/// One CSV line, quoted where needed
+pub fn csv_line(fields: &[&str]) -> String {
+    let mut writer = csv::WriterBuilder::new().from_writer(Vec::new());
+    // Writing to memory cannot fail
+    let _ = writer.write_record(fields);
+    String::from_utf8(writer.into_inner().unwrap_or_default()).unwrap_or_default()
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    domain: Option<String>,
+}
+
+/// `GET /api/admin/export`: every link that is not deleted, oldest first, streamed
+pub async fn export_links(
+    _auth: Authenticated,
+    client: web::Data<Client>,
+    domains: web::Data<Domains>,
+    query: web::Query<ExportQuery>,
+) -> Result<HttpResponse> {
+    let domain = match domains.by_name(query.domain.as_deref()) {
+        Ok(domain) => domain,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+    let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
+    let options = mongodb::options::FindOptions::builder().sort(doc! {"created_at": 1, "_id": 1}).build();
+    let cursor = collection
+        .find(doc! {"domain": domain.filter_value(), "status": {"$ne": "deleted"}}, options)
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e)))?;
+    let rows = cursor.map(|url_doc| {
+        url_doc
+            .map(|url_doc| {
+                let (created_at, clicks) = (rfc3339(url_doc.created_at), url_doc.transition_count.to_string());
+                let status = status_column(url_doc.status);
+                Bytes::from(csv_line(&[&url_doc.short_code, &url_doc.original_url, &created_at, &clicks, status]))
+            })
+            .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e)))
+    });
+    let body = stream::once(async { Ok::<_, actix_web::Error>(Bytes::from(csv_line(&HEADER))) }).chain(rows);
+    let filename = format!("links-{}.csv", domain.name);
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", filename)))
+        .streaming(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv() {
+        let data = b"code,url,created_at,clicks\nabc,https://Example.com/a/,2020-01-02T03:04:05Z,12\nxyz,https://example.com/b,,\n,https://example.com/c\nbad code,https://example.com\nq1,ftp://example.com\nq2,https://example.com,yesterday\nq3,https://example.com,,-1\n\n";
+        let (rows, invalid) = parse_csv(&UrlService::new_dummy(), data);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].line, 2);
+        assert_eq!(rows[0].url, "https://example.com/a");
+        assert_eq!(rows[0].created_at, Some(MongoDateTime::from_millis(1_577_934_245_000)));
+        assert_eq!(rows[0].clicks, 12);
+        assert_eq!((rows[1].created_at, rows[1].clicks), (None, 0));
+        assert_eq!(invalid.iter().map(|p| p.line).collect::<Vec<_>>(), vec![4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_status_column() {
+        let (rows, invalid) = parse_csv(&UrlService::new_dummy(), b"a1,https://example.com,,,disabled\na2,https://example.com,,,\na3,https://example.com,,,deleted\n");
+        assert_eq!(rows.iter().map(|r| r.status).collect::<Vec<_>>(), vec![LinkStatus::Disabled, LinkStatus::Active]);
+        assert_eq!(invalid.iter().map(|p| p.line).collect::<Vec<_>>(), vec![3]);
+        let line = csv_line(&["a1", "https://example.com/", "", "3", status_column(LinkStatus::Disabled)]);
+        assert_eq!(parse_csv(&UrlService::new_dummy(), line.as_bytes()).0[0].status, LinkStatus::Disabled);
+    }
+
+    #[test]
+    fn test_reserved_codes() {
+        let (rows, invalid) = parse_csv(&UrlService::new_dummy(), b"api,https://example.com\nMetrics,https://example.com\nhealthy,https://example.com\n");
+        assert_eq!(rows.iter().map(|r| r.code.as_str()).collect::<Vec<_>>(), vec!["healthy"]);
+        assert_eq!(invalid.iter().map(|p| p.line).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_header_is_optional() {
+        let (rows, invalid) = parse_csv(&UrlService::new_dummy(), b"abc,https://example.com\n\"q,1\",https://example.com");
+        assert_eq!(rows.len(), 1);
+        assert_eq!(invalid[0].code, "q,1");
+    }
+
+    #[test]
+    fn test_split_conflicts() {
+        let (rows, _) = parse_csv(&UrlService::new_dummy(), b"a1,https://example.com/1\nb2,https://example.com/2\na1,https://example.com/3\n");
+        let taken = HashSet::from(["b2".to_string()]);
+        let (accepted, conflicts) = split_conflicts(rows, &taken);
+        assert_eq!(accepted.iter().map(|r| r.code.as_str()).collect::<Vec<_>>(), vec!["a1"]);
+        assert_eq!(conflicts, vec![RowProblem::new(2, "b2", "Code already exists"), RowProblem::new(3, "a1", "Code appears earlier in the file")]);
+    }
+
+    #[test]
+    fn test_csv_line_round_trip() {
+        let line = csv_line(&["abc", "https://example.com/?a=1,2&b=\"x\"", "2020-01-02T03:04:05+00:00", "7"]);
+        assert_eq!(line, "abc,\"https://example.com/?a=1,2&b=\"\"x\"\"\",2020-01-02T03:04:05+00:00,7\n");
+        let (rows, invalid) = parse_csv(&UrlService::new_dummy(), line.as_bytes());
+        assert!(invalid.is_empty());
+        assert_eq!(rows[0].url, "https://example.com/?a=1,2&b=%22x%22");
+    }
+}
@@ -0,0 +1,436 @@
+//! Links Module
+//!
+//! Management endpoints for existing links. Changing a destination keeps the
+//! code and counters, records the previous destination in the link's
+//! `history`, and drops the link from this instance's redirect cache (other
+//! instances pick the change up when their cached entry expires).
+//!
+//! Links can also be disabled or deleted. Both answer with the configured
+//! 404/410 page instead of redirecting. Deleted links are soft-deleted: they
+//! keep their code for the retention window, so an old printed link cannot be
+//! taken over by a new one, and can be restored until they are purged.
+//!
+//! Operators holding the API token manage every link; logged-in users only
+//! the links they own and, as editors, their workspaces' links. Everybody
+//! else's links look unknown.
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use mongodb::bson::{doc, Bson, DateTime as MongoDateTime, Document};
+use mongodb::{Client, Collection, Database};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::auth::LinkManager;
+use crate::config::LinksConfig;
+use crate::domains::{Domain, Domains};
+use crate::passthrough::Passthrough;
+use crate::public_url::PublicUrl;
+use crate::redirect_cache::RedirectCache;
+use crate::redirect_type::RedirectType;
+use crate::url_service::UrlService;
+use crate::{DomainQuery, UrlDoc};
+
+const DEFAULT_INACTIVE_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><meta name="robots" content="noindex"><title>Link unavailable</title></head>
+<body><h1>This link is no longer available</h1><p>It has been disabled or removed by its owner.</p></body>
+</html>
+"#;
+
+#[derive(Error, Debug)]
+pub enum LinkError {
+    #[error("Inactive link status must be 404 or 410, got {0}")]
+    InvalidStatus(u16),
+    #[error("Failed to read inactive link page {0}: {1}")]
+    ReadPage(String, std::io::Error),
+}
+
+/// Whether a link redirects; active links are stored without a `status` field
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkStatus {
+    #[default]
+    Active,
+    Disabled,
+    Deleted,
+}
+
+impl LinkStatus {
+    pub fn is_active(&self) -> bool {
+        *self == LinkStatus::Active
+    }
+}
+
+/// Who disabled a link; only admins can re-enable what an admin disabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisabledBy {
+    Owner,
+    Admin,
+}
+
+impl DisabledBy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisabledBy::Owner => "owner",
+            DisabledBy::Admin => "admin",
+        }
+    }
+}
+
+/// Response for disabled and deleted links
+pub struct InactivePage {
+    status: StatusCode,
+    body: String,
+}
+
+impl InactivePage {
+    pub fn from_config(config: &LinksConfig) -> Result<Self, LinkError> {
+        let status = match config.inactive_status {
+            404 => StatusCode::NOT_FOUND,
+            410 => StatusCode::GONE,
+            other => return Err(LinkError::InvalidStatus(other)),
+        };
+        let body = match &config.inactive_page {
+            Some(path) => std::fs::read_to_string(path).map_err(|e| LinkError::ReadPage(path.display().to_string(), e))?,
+            None => DEFAULT_INACTIVE_PAGE.to_string(),
+        };
+        Ok(InactivePage { status, body })
+    }
+
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).content_type("text/html; charset=utf-8").body(self.body.clone())
+    }
+}
+
+/// A destination a link pointed to before it was edited
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DestinationChange {
+    pub original_url: String,
+    pub replaced_at: MongoDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryEntry {
+    original_url: String,
+    replaced_at: String,
+}
+
+pub fn rfc3339(date: MongoDateTime) -> String {
+    DateTime::<Utc>::from_timestamp_millis(date.timestamp_millis()).unwrap().to_rfc3339()
+}
+
+/// History for API responses, oldest first
+pub fn history_entries(history: &[DestinationChange]) -> Vec<HistoryEntry> {
+    history
+        .iter()
+        .map(|change| HistoryEntry { original_url: change.original_url.clone(), replaced_at: rfc3339(change.replaced_at) })
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct UpdateLinkRequest {
+    url: String,
+}
+
+#[derive(Serialize)]
+pub struct LinkResponse {
+    short_code: String,
+    short_url: String,
+    domain: String,
+    original_url: String,
+    created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<String>,
+    transition_count: i64,
+    redirect_type: RedirectType,
+    passthrough: Passthrough,
+    status: LinkStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disabled_by: Option<DisabledBy>,
+    history: Vec<HistoryEntry>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    workspace_id: Option<String>,
+}
+
+impl LinkResponse {
+    pub fn new(url_doc: &UrlDoc, domain: &Domain, public_url: &PublicUrl, req: &HttpRequest) -> Self {
+        LinkResponse {
+            short_code: url_doc.short_code.clone(),
+            short_url: domain.short_url(public_url, req, &url_doc.short_code),
+            domain: domain.name.clone(),
+            original_url: url_doc.original_url.clone(),
+            created_at: rfc3339(url_doc.created_at),
+            updated_at: url_doc.updated_at.map(rfc3339),
+            transition_count: url_doc.transition_count,
+            redirect_type: url_doc.redirect_type,
+            passthrough: url_doc.passthrough,
+            status: url_doc.status,
+            disabled_by: url_doc.disabled_by,
+            history: history_entries(&url_doc.history),
+            tags: url_doc.tags.clone(),
+            owner_id: url_doc.owner_id.clone(),
+            workspace_id: url_doc.workspace_id.map(|id| id.to_hex()),
+        }
+    }
+}
+
+/// `PATCH /api/links/{short_code}`: point an existing link at a new destination
+#[allow(clippy::too_many_arguments)]
+pub async fn update_link(
+    manager: LinkManager,
+    client: web::Data<Client>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    url_service: web::Data<UrlService>,
+    cache: web::Data<RedirectCache>,
+    path: web::Path<String>,
+    query: web::Query<DomainQuery>,
+    req: web::Json<UpdateLinkRequest>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse> {
+    let short_code = path.into_inner();
+    let domain = match domains.by_name(query.domain.as_deref()) {
+        Ok(domain) => domain,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+    if let Err(e) = url_service.validate_url(&req.url) {
+        return Ok(HttpResponse::BadRequest().body(format!("Invalid URL: {}", e)));
+    }
+    let normalized_url = match url_service.normalize_url(&req.url) {
+        Ok(url) => url,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(format!("URL normalization failed: {}", e))),
+    };
+    crate::admin::check_destination(&client, &normalized_url).await?;
+    if let Err(e) = url_service.check_resolved_url(&normalized_url).await {
+        return Ok(HttpResponse::BadRequest().body(e.to_string()));
+    }
+    let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
+    let filter = doc! {"short_code": &short_code, "domain": domain.filter_value()};
+    let query_error = |e: mongodb::error::Error| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e));
+    let Some(mut url_doc) = collection.find_one(filter, None).await.map_err(query_error)? else {
+        return Ok(HttpResponse::NotFound().body("Short URL not found"));
+    };
+    // Other users' links look unknown
+    let may_manage = manager.may_manage(&client, url_doc.owner_id.as_deref(), url_doc.workspace_id).await.map_err(query_error)?;
+    if url_doc.status == LinkStatus::Deleted || !may_manage {
+        return Ok(HttpResponse::NotFound().body("Short URL not found"));
+    }
+    if url_doc.original_url == normalized_url {
+        return Ok(HttpResponse::Ok().json(LinkResponse::new(&url_doc, domain, &public_url, &http_req)));
+    }
+    let now = MongoDateTime::now();
+    let change = DestinationChange { original_url: url_doc.original_url.clone(), replaced_at: now };
+    // Matching on the old destination makes concurrent edits fail instead of losing a history entry
+    let result = collection
+        .update_one(
+            doc! {"_id": url_doc.id, "original_url": &change.original_url},
+            doc! {
+                "$set": {"original_url": &normalized_url, "updated_at": now},
+                "$push": {"history": {"original_url": &change.original_url, "replaced_at": now}},
+            },
+            None,
+        )
+        .await
+        .map_err(query_error)?;
+    if result.matched_count == 0 {
+        return Ok(HttpResponse::Conflict().body("The link was changed concurrently, please retry"));
+    }
+    cache.invalidate(&domain.name, &short_code);
+    url_doc.original_url = normalized_url;
+    url_doc.updated_at = Some(now);
+    url_doc.history.push(change);
+    Ok(HttpResponse::Ok().json(LinkResponse::new(&url_doc, domain, &public_url, &http_req)))
+}
+
+/// Move a link to `target` for `manager`; shared by the owner and admin endpoints
+#[allow(clippy::too_many_arguments)]
+pub async fn change_status(
+    manager: LinkManager,
+    client: web::Data<Client>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    cache: web::Data<RedirectCache>,
+    short_code: String,
+    query: web::Query<DomainQuery>,
+    http_req: HttpRequest,
+    target: LinkStatus,
+) -> Result<HttpResponse> {
+    let domain = match domains.by_name(query.domain.as_deref()) {
+        Ok(domain) => domain,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+    let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
+    let filter = doc! {"short_code": &short_code, "domain": domain.filter_value()};
+    let query_error = |e: mongodb::error::Error| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e));
+    let Some(mut url_doc) = collection.find_one(filter.clone(), None).await.map_err(query_error)? else {
+        return Ok(HttpResponse::NotFound().body("Short URL not found"));
+    };
+    if !manager.may_manage(&client, url_doc.owner_id.as_deref(), url_doc.workspace_id).await.map_err(query_error)? {
+        return Ok(HttpResponse::NotFound().body("Short URL not found"));
+    }
+    // Deleted links can only be restored
+    if url_doc.status == LinkStatus::Deleted && target == LinkStatus::Disabled {
+        return Ok(HttpResponse::NotFound().body("Short URL not found"));
+    }
+    let by = if manager == LinkManager::Operator { DisabledBy::Admin } else { DisabledBy::Owner };
+    if target == LinkStatus::Active && url_doc.status != LinkStatus::Active {
+        // Moderation stays in place until an admin lifts it
+        if url_doc.disabled_by == Some(DisabledBy::Admin) && by != DisabledBy::Admin {
+            return Ok(HttpResponse::Forbidden().body("The link was disabled by an administrator"));
+        }
+        crate::admin::check_destination(&client, &url_doc.original_url).await?;
+    }
+    // An admin disabling a link the owner disabled takes the decision over
+    let takes_over = target == LinkStatus::Disabled && by == DisabledBy::Admin && url_doc.disabled_by != Some(by);
+    if url_doc.status != target || takes_over {
+        let now = MongoDateTime::now();
+        let update = match target {
+            LinkStatus::Active => doc! {"$unset": {"status": "", "deleted_at": "", "disabled_by": ""}},
+            LinkStatus::Disabled => doc! {"$set": {"status": "disabled", "disabled_by": by.as_str()}},
+            LinkStatus::Deleted => doc! {"$set": {"status": "deleted", "deleted_at": now}},
+        };
+        let mut update_filter = filter;
+        if manager != LinkManager::Operator {
+            // An admin may have moderated the link since it was read; their decision must not be undone
+            let status = match url_doc.status {
+                LinkStatus::Active => Bson::Null,
+                LinkStatus::Disabled => "disabled".into(),
+                LinkStatus::Deleted => "deleted".into(),
+            };
+            update_filter.insert("status", status);
+            update_filter.insert("disabled_by", doc! {"$ne": DisabledBy::Admin.as_str()});
+        }
+        let result = collection.update_one(update_filter, update, None).await.map_err(query_error)?;
+        if result.matched_count == 0 {
+            return Ok(HttpResponse::Conflict().body("The link was changed concurrently, please retry"));
+        }
+        cache.invalidate(&domain.name, &short_code);
+        url_doc.status = target;
+        url_doc.deleted_at = (target == LinkStatus::Deleted).then_some(now);
+        match target {
+            LinkStatus::Active => url_doc.disabled_by = None,
+            LinkStatus::Disabled => url_doc.disabled_by = Some(by),
+            LinkStatus::Deleted => {}
+        }
+    }
+    if target == LinkStatus::Deleted {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+    Ok(HttpResponse::Ok().json(LinkResponse::new(&url_doc, domain, &public_url, &http_req)))
+}
+
+/// `DELETE /api/links/{short_code}`: soft-delete; the code is kept until the link is purged
+#[allow(clippy::too_many_arguments)]
+pub async fn delete_link(
+    manager: LinkManager,
+    client: web::Data<Client>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    cache: web::Data<RedirectCache>,
+    path: web::Path<String>,
+    query: web::Query<DomainQuery>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse> {
+    change_status(manager, client, public_url, domains, cache, path.into_inner(), query, http_req, LinkStatus::Deleted).await
+}
+
+/// `POST /api/links/{short_code}/disable`
+#[allow(clippy::too_many_arguments)]
+pub async fn disable_link(
+    manager: LinkManager,
+    client: web::Data<Client>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    cache: web::Data<RedirectCache>,
+    path: web::Path<String>,
+    query: web::Query<DomainQuery>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse> {
+    change_status(manager, client, public_url, domains, cache, path.into_inner(), query, http_req, LinkStatus::Disabled).await
+}
+
+/// `POST /api/links/{short_code}/enable`: re-enable a disabled link or restore a deleted one before it is purged
+#[allow(clippy::too_many_arguments)]
+pub async fn enable_link(
+    manager: LinkManager,
+    client: web::Data<Client>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    cache: web::Data<RedirectCache>,
+    path: web::Path<String>,
+    query: web::Query<DomainQuery>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse> {
+    change_status(manager, client, public_url, domains, cache, path.into_inner(), query, http_req, LinkStatus::Active).await
+}
+
+/// Remove links deleted more than `retention` ago, freeing their codes; returns the number removed
+pub async fn purge_deleted(db: &Database, retention: Duration) -> Result<u64, mongodb::error::Error> {
+    let cutoff = MongoDateTime::from_millis(MongoDateTime::now().timestamp_millis() - retention.as_millis() as i64);
+    let result = db
+        .collection::<Document>("urls")
+        .delete_many(doc! {"status": "deleted", "deleted_at": {"$lt": cutoff}}, None)
+        .await?;
+    Ok(result.deleted_count)
+}
+
+/// Purge expired deleted links periodically
+pub fn spawn_purger(db: Database, config: &LinksConfig) {
+    let retention = Duration::from_secs(config.deleted_retention_days * 24 * 60 * 60);
+    let interval = Duration::from_secs(config.purge_interval_secs);
+    tokio::spawn(async move {
+        loop {
+            match purge_deleted(&db, retention).await {
+                Ok(0) => {}
+                Ok(purged) => tracing::info!(purged, "Purged deleted links"),
+                Err(e) => tracing::warn!("Failed to purge deleted links: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_entries() {
+        let history = vec![DestinationChange {
+            original_url: "https://exmaple.com".into(),
+            replaced_at: MongoDateTime::from_millis(1_700_000_000_000),
+        }];
+        let entries = history_entries(&history);
+        assert_eq!(entries[0].original_url, "https://exmaple.com");
+        assert_eq!(entries[0].replaced_at, "2023-11-14T22:13:20+00:00");
+    }
+
+    #[test]
+    fn test_inactive_page() {
+        let mut config = LinksConfig::default();
+        let page = InactivePage::from_config(&config).unwrap();
+        assert_eq!(page.response().status(), StatusCode::GONE);
+        config.inactive_status = 404;
+        assert_eq!(InactivePage::from_config(&config).unwrap().response().status(), StatusCode::NOT_FOUND);
+        config.inactive_status = 302;
+        assert!(InactivePage::from_config(&config).is_err());
+        config.inactive_status = 410;
+        config.inactive_page = Some("/nonexistent/page.html".into());
+        assert!(matches!(InactivePage::from_config(&config), Err(LinkError::ReadPage(..))));
+    }
+
+    #[test]
+    fn test_status_serialization() {
+        assert_eq!(serde_json::to_string(&LinkStatus::Disabled).unwrap(), "\"disabled\"");
+        assert_eq!(serde_json::to_string(&DisabledBy::Admin).unwrap(), format!("\"{}\"", DisabledBy::Admin.as_str()));
+        assert!(LinkStatus::default().is_active());
+    }
+}
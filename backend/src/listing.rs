@@ -0,0 +1,354 @@
+//! Listing Module
+//!
+//! `GET /api/links` returns the caller's links a page at a time: a logged-in
+//! user's own links, the links of an API key's owner, or with
+//! `workspace_id` the links of a workspace the caller belongs to.
+//!
+//! Pages are sorted by creation date or clicks, with the id breaking ties,
+//! and continue from an opaque cursor holding the last link's sort value and
+//! id. Unlike offsets, cursors neither skip nor repeat links when links are
+//! created while paging. Deleted links are only listed when asked for with
+//! `status=deleted`.
+//!
+//! Links can carry up to `MAX_TAGS` tags, set when shortening, to filter by.
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::DateTime;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime as MongoDateTime, Document, Regex};
+use mongodb::options::FindOptions;
+use mongodb::{Client, Collection};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::accounts::User;
+use crate::admin::escape_regex;
+use crate::api_keys::{self, ApiKey, Scope};
+use crate::domains::Domains;
+use crate::links::{LinkResponse, LinkStatus};
+use crate::public_url::PublicUrl;
+use crate::{workspaces, UrlDoc};
+
+pub const MAX_TAGS: usize = 10;
+const MAX_TAG_LENGTH: usize = 50;
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ListError {
+    #[error("Log in or send an API key to list links")]
+    NoCaller,
+    #[error("Invalid cursor")]
+    InvalidCursor,
+    #[error("Invalid {0}: expected an RFC 3339 date")]
+    InvalidDate(&'static str),
+    #[error("Invalid tag {0:?}: tags are 1 to {MAX_TAG_LENGTH} of a-z, 0-9, - and _")]
+    InvalidTag(String),
+    #[error("At most {MAX_TAGS} tags are allowed")]
+    TooManyTags,
+}
+
+impl ResponseError for ListError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ListError::NoCaller => StatusCode::UNAUTHORIZED,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).body(self.to_string())
+    }
+}
+
+/// Lowercased, deduplicated and sorted, so equal tag sets are stored alike
+pub fn normalize_tags(tags: &[String]) -> Result<Vec<String>, ListError> {
+    let mut normalized = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        let valid = !tag.is_empty()
+            && tag.len() <= MAX_TAG_LENGTH
+            && tag.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_');
+        if !valid {
+            return Err(ListError::InvalidTag(tag));
+        }
+        normalized.push(tag);
+    }
+    normalized.sort();
+    normalized.dedup();
+    if normalized.len() > MAX_TAGS {
+        return Err(ListError::TooManyTags);
+    }
+    Ok(normalized)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    #[default]
+    CreatedAt,
+    Clicks,
+}
+
+impl SortField {
+    fn key(self) -> &'static str {
+        match self {
+            SortField::CreatedAt => "created_at",
+            SortField::Clicks => "transition_count",
+        }
+    }
+
+    fn value_of(self, url_doc: &UrlDoc) -> i64 {
+        match self {
+            SortField::CreatedAt => url_doc.created_at.timestamp_millis(),
+            SortField::Clicks => url_doc.transition_count,
+        }
+    }
+
+    fn bson(self, value: i64) -> Bson {
+        match self {
+            SortField::CreatedAt => Bson::DateTime(MongoDateTime::from_millis(value)),
+            SortField::Clicks => Bson::Int64(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Where the previous page ended
+#[derive(Debug, PartialEq)]
+struct Cursor {
+    value: i64,
+    id: ObjectId,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!("{}:{}", self.value, self.id.to_hex()))
+    }
+
+    fn decode(cursor: &str) -> Result<Self, ListError> {
+        let decoded = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| ListError::InvalidCursor)?;
+        let decoded = String::from_utf8(decoded).map_err(|_| ListError::InvalidCursor)?;
+        let (value, id) = decoded.split_once(':').ok_or(ListError::InvalidCursor)?;
+        Ok(Cursor {
+            value: value.parse().map_err(|_| ListError::InvalidCursor)?,
+            id: ObjectId::parse_str(id).map_err(|_| ListError::InvalidCursor)?,
+        })
+    }
+
+    /// Links strictly after the cursor in the page order
+    fn filter(&self, sort: SortField, order: SortOrder) -> Document {
+        let op = match order {
+            SortOrder::Asc => "$gt",
+            SortOrder::Desc => "$lt",
+        };
+        let value = sort.bson(self.value);
+        doc! {"$or": [
+            {sort.key(): {op: value.clone()}},
+            {sort.key(): value, "_id": {op: self.id}},
+        ]}
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    workspace_id: Option<String>,
+    domain: Option<String>,
+    tag: Option<String>,
+    created_after: Option<String>,
+    created_before: Option<String>,
+    /// Case-insensitive substring of the destination
+    q: Option<String>,
+    status: Option<LinkStatus>,
+    #[serde(default)]
+    sort: SortField,
+    #[serde(default)]
+    order: SortOrder,
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+fn parse_date(name: &'static str, value: &str) -> Result<MongoDateTime, ListError> {
+    let date = DateTime::parse_from_rfc3339(value).map_err(|_| ListError::InvalidDate(name))?;
+    Ok(MongoDateTime::from_millis(date.timestamp_millis()))
+}
+
+/// The filters besides who the links belong to
+fn list_filter(query: &ListQuery, domains: &Domains) -> Result<Document, actix_web::Error> {
+    let mut filter = doc! {};
+    if let Some(name) = &query.domain {
+        let domain = domains.by_name(Some(name)).map_err(actix_web::error::ErrorBadRequest)?;
+        filter.insert("domain", domain.filter_value());
+    }
+    if let Some(tag) = &query.tag {
+        let tag = normalize_tags(std::slice::from_ref(tag))?.remove(0);
+        filter.insert("tags", tag);
+    }
+    let mut created = doc! {};
+    if let Some(after) = &query.created_after {
+        created.insert("$gte", parse_date("created_after", after)?);
+    }
+    if let Some(before) = &query.created_before {
+        created.insert("$lt", parse_date("created_before", before)?);
+    }
+    if !created.is_empty() {
+        filter.insert("created_at", created);
+    }
+    if let Some(q) = query.q.as_deref().filter(|q| !q.is_empty()) {
+        filter.insert("original_url", Regex { pattern: escape_regex(q), options: "i".into() });
+    }
+    match query.status {
+        Some(LinkStatus::Active) => filter.insert("status", Bson::Null),
+        Some(LinkStatus::Disabled) => filter.insert("status", "disabled"),
+        Some(LinkStatus::Deleted) => filter.insert("status", "deleted"),
+        None => filter.insert("status", doc! {"$ne": "deleted"}),
+    };
+    if let Some(cursor) = &query.cursor {
+        filter.extend(Cursor::decode(cursor)?.filter(query.sort, query.order));
+    }
+    Ok(filter)
+}
+
+#[derive(Serialize)]
+pub struct ListResponse {
+    links: Vec<LinkResponse>,
+    /// Pass as `cursor` for the next page; absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+/// `GET /api/links`
+pub async fn list_links(
+    client: web::Data<Client>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    api_key: Option<web::ReqData<ApiKey>>,
+    user: Option<web::ReqData<User>>,
+    query: web::Query<ListQuery>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse> {
+    let api_key = api_keys::authorize(api_key.as_deref(), Scope::Read, false)?;
+    let user = user.as_deref();
+    let mut filter = list_filter(&query, &domains)?;
+    match &query.workspace_id {
+        Some(id) => {
+            let workspace_id = workspaces::parse_id(id)?;
+            let is_key_owner = api_key.is_some_and(|key| key.owner_id == workspace_id.to_hex());
+            let is_member = match user {
+                Some(user) => workspaces::role_of(&client, workspace_id, user.id)
+                    .await
+                    .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e)))?
+                    .is_some(),
+                None => false,
+            };
+            if !is_key_owner && !is_member {
+                return Err(workspaces::WorkspaceError::NotFound.into());
+            }
+            filter.insert("workspace_id", workspace_id);
+        }
+        None => {
+            filter.insert("owner_id", crate::caller_owner(user, api_key).ok_or(ListError::NoCaller)?);
+        }
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let direction = match query.order {
+        SortOrder::Asc => 1,
+        SortOrder::Desc => -1,
+    };
+    // One extra link tells whether there is a next page
+    let options = FindOptions::builder()
+        .sort(doc! {query.sort.key(): direction, "_id": direction})
+        .limit(limit + 1)
+        .build();
+    let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
+    let mut found: Vec<UrlDoc> = collection
+        .find(filter, options)
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e)))?
+        .try_collect()
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e)))?;
+    let next_cursor = if found.len() as i64 > limit {
+        found.truncate(limit as usize);
+        found.last().and_then(|last| last.id.map(|id| Cursor { value: query.sort.value_of(last), id }.encode()))
+    } else {
+        None
+    };
+    let links = found
+        .iter()
+        .map(|url_doc| {
+            let domain = domains.by_name(url_doc.domain.as_deref()).unwrap_or_else(|_| domains.primary());
+            LinkResponse::new(url_doc, domain, &public_url, &http_req)
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(ListResponse { links, next_cursor }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(pairs: &str) -> ListQuery {
+        web::Query::<ListQuery>::from_query(pairs).unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_normalize_tags() {
+        let tags = normalize_tags(&["Spring".into(), "news-letter".into(), "spring".into()]).unwrap();
+        assert_eq!(tags, vec!["news-letter", "spring"]);
+        assert_eq!(normalize_tags(&["a b".into()]), Err(ListError::InvalidTag("a b".into())));
+        assert_eq!(normalize_tags(&["".into()]), Err(ListError::InvalidTag("".into())));
+        let many: Vec<String> = (0..=MAX_TAGS).map(|i| format!("t{}", i)).collect();
+        assert_eq!(normalize_tags(&many), Err(ListError::TooManyTags));
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = Cursor { value: 1_700_000_000_000, id: ObjectId::new() };
+        assert_eq!(Cursor::decode(&cursor.encode()), Ok(cursor));
+        assert_eq!(Cursor::decode("not a cursor"), Err(ListError::InvalidCursor));
+        assert_eq!(Cursor::decode(&URL_SAFE_NO_PAD.encode("12:nope")), Err(ListError::InvalidCursor));
+    }
+
+    #[test]
+    fn test_cursor_filter() {
+        let id = ObjectId::new();
+        let filter = Cursor { value: 5, id }.filter(SortField::Clicks, SortOrder::Desc);
+        assert_eq!(
+            filter,
+            doc! {"$or": [{"transition_count": {"$lt": 5_i64}}, {"transition_count": 5_i64, "_id": {"$lt": id}}]}
+        );
+        let filter = Cursor { value: 0, id }.filter(SortField::CreatedAt, SortOrder::Asc);
+        assert_eq!(
+            filter,
+            doc! {"$or": [{"created_at": {"$gt": MongoDateTime::from_millis(0)}}, {"created_at": MongoDateTime::from_millis(0), "_id": {"$gt": id}}]}
+        );
+    }
+
+    #[test]
+    fn test_list_filter() {
+        let domains = Domains::new(&[]).unwrap();
+        let filter = list_filter(&query("tag=Promo&created_after=2024-01-01T00:00:00Z&q=a.b"), &domains).unwrap();
+        assert_eq!(filter.get_str("tags").unwrap(), "promo");
+        assert_eq!(
+            filter.get_document("created_at").unwrap(),
+            &doc! {"$gte": MongoDateTime::from_millis(1_704_067_200_000)}
+        );
+        assert_eq!(filter.get_document("status").unwrap(), &doc! {"$ne": "deleted"});
+        assert!(matches!(filter.get("original_url"), Some(Bson::RegularExpression(regex)) if regex.pattern == r"a\.b"));
+        let filter = list_filter(&query("status=deleted"), &domains).unwrap();
+        assert_eq!(filter.get_str("status").unwrap(), "deleted");
+        assert!(list_filter(&query("created_before=yesterday"), &domains).is_err());
+        assert!(list_filter(&query("cursor=bogus"), &domains).is_err());
+    }
+}
@@ -6,13 +6,12 @@ use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, Env
 use std::fs::File;
 use actix_web::HttpMessage;
 
-pub fn init_logging() {
+pub fn init_logging(is_dev: bool) {
     let env_filter = EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| {
             EnvFilter::new("url_shortener=info,actix_web=info,actix_http=info")
         });
 
-    let is_dev = std::env::var("APP_ENV").unwrap_or_else(|_| "development".into()) == "development";
     let registry = tracing_subscriber::registry().with(env_filter);
 
     if is_dev {
@@ -71,8 +70,8 @@ pub fn set_panic_hook() {
     }));
 }
 
-pub fn init_logging_with_fallback() {
-    if let Err(e) = try_init_logging() {
+pub fn init_logging_with_fallback(is_dev: bool) {
+    if let Err(e) = try_init_logging(is_dev) {
         eprintln!("Failed to initialize structured logging: {}", e);
         eprintln!("Falling back to simple stderr logging");
         let stderr_layer = tracing_subscriber::fmt::layer()
@@ -86,8 +85,8 @@ pub fn init_logging_with_fallback() {
     }
 }
 
-fn try_init_logging() -> Result<(), Box<dyn std::error::Error>> {
-    init_logging();
+fn try_init_logging(is_dev: bool) -> Result<(), Box<dyn std::error::Error>> {
+    init_logging(is_dev);
     Ok(())
 }
 
@@ -1,26 +1,131 @@
 mod logging;
 mod tracing;
+mod config;
+mod cli;
+mod cors;
+mod shutdown;
+mod redirect_cache;
+mod metrics;
+mod click_buffer;
+mod bloom_filter;
+mod redirect_type;
+mod preview;
+mod passthrough;
+mod utm;
+mod qr;
+mod auth;
+mod links;
+mod api_keys;
+mod jwt;
+mod accounts;
+mod workspaces;
+mod admin;
+mod listing;
+mod batch;
+mod link_csv;
+mod importers;
 use actix_web::{web, App, HttpResponse, HttpServer, Responder, Result};
-use mongodb::{bson::{doc, oid::ObjectId, DateTime as MongoDateTime}, Client, Collection, options::IndexOptions, IndexModel};
+use mongodb::{bson::{doc, oid::ObjectId, Bson, DateTime as MongoDateTime}, Client, Collection, options::IndexOptions, IndexModel};
 use serde::{Deserialize, Serialize};
-use std::env;
 use rand::{distributions::Alphanumeric, Rng};
 use mongodb::options::{ClientOptions, ServerApi, ServerApiVersion};
 use url_service::UrlService;
-use actix_cors::Cors;
 use tracing_actix_web::TracingLogger;
-use log::error;
+use log::{debug, error, info};
 use chrono::{Utc, DateTime};
 mod url_service;
+mod public_url;
+mod domains;
+use public_url::PublicUrl;
+use domains::Domains;
+use redirect_cache::{CachedLink, Lookup, RedirectCache};
+use click_buffer::ClickBuffer;
+use bloom_filter::CodeFilter;
+use redirect_type::RedirectType;
+use passthrough::{Passthrough, PassthroughError};
+use utm::UtmParams;
+use qr::QrOptions;
+use links::{DestinationChange, DisabledBy, HistoryEntry, InactivePage, LinkStatus};
+use api_keys::{ApiKey, Scope};
+use accounts::User;
+use config::Config;
+use std::sync::Arc;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct UrlDoc {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     id: Option<ObjectId>,
     short_code: String,
+    /// Short domain the code belongs to; absent for the primary domain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    domain: Option<String>,
     original_url: String,
     created_at: MongoDateTime,
     transition_count: i64,
+    /// HTTP status used when redirecting; links created before it was configurable use 302
+    #[serde(default)]
+    redirect_type: RedirectType,
+    /// What of the request path and query is forwarded to the destination
+    #[serde(default)]
+    passthrough: Passthrough,
+    /// When the destination was last edited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    updated_at: Option<MongoDateTime>,
+    /// Earlier destinations, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    history: Vec<DestinationChange>,
+    /// Absent for active links
+    #[serde(default, skip_serializing_if = "LinkStatus::is_active")]
+    status: LinkStatus,
+    /// Who disabled the link, kept while it is disabled or deleted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    disabled_by: Option<DisabledBy>,
+    /// When the link was soft-deleted; it is purged once the retention window has passed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<MongoDateTime>,
+    /// API key the link was created with; absent for anonymous links
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_by: Option<ObjectId>,
+    /// Owner of that key, the id of the user who created the link, or its workspace; only they can read its analytics
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner_id: Option<String>,
+    /// Workspace owning the link; its members read the analytics and its editors manage it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    workspace_id: Option<ObjectId>,
+    /// Lowercase, sorted and unique
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+}
+
+impl UrlDoc {
+    /// Whether a caller acting for `owner_id` may see the link's analytics; owned links look unknown to everybody else
+    fn is_visible_to(&self, owner_id: Option<&str>) -> bool {
+        match &self.owner_id {
+            Some(link_owner) => owner_id == Some(link_owner.as_str()),
+            None => true,
+        }
+    }
+}
+
+/// Who the caller acts for: a logged-in user, else the owner of their API key
+fn caller_owner(user: Option<&User>, api_key: Option<&ApiKey>) -> Option<String> {
+    user.map(User::owner_id).or_else(|| api_key.map(|key| key.owner_id.clone()))
+}
+
+/// Whether the caller may read a link's analytics: any member of its workspace may, besides its owner
+async fn can_read(client: &Client, url_doc: &UrlDoc, user: Option<&User>, api_key: Option<&ApiKey>) -> Result<bool> {
+    if url_doc.is_visible_to(caller_owner(user, api_key).as_deref())
+        || url_doc.is_visible_to(api_key.map(|key| key.owner_id.as_str()))
+    {
+        return Ok(true);
+    }
+    match (url_doc.workspace_id, user) {
+        (Some(workspace_id), Some(user)) => Ok(workspaces::role_of(client, workspace_id, user.id)
+            .await
+            .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e)))?
+            .is_some()),
+        _ => Ok(false),
+    }
 }
 
 /// Represents an analytics record for URL access statistics
@@ -41,21 +146,56 @@ struct AnalyticsDoc {
 #[derive(Deserialize)]
 struct ShortenRequest {
     url: String,
+    /// One of the configured short domains; defaults to the primary domain
+    #[serde(default)]
+    domain: Option<String>,
+    /// 301, 302, 307 or 308; defaults to the configured redirect type
+    #[serde(default)]
+    redirect_type: Option<RedirectType>,
+    /// off, path, query or path_and_query; defaults to the configured passthrough
+    #[serde(default)]
+    passthrough: Option<Passthrough>,
+    /// Campaign tags merged into `url` before it is normalized
+    #[serde(default)]
+    utm: UtmParams,
+    /// Include a PNG QR code of the short URL as a data URI
+    #[serde(default)]
+    qr: bool,
+    /// Create the link in this workspace; needs the editor role or a key owned by the workspace
+    #[serde(default)]
+    workspace_id: Option<String>,
+    /// Labels to filter the link by in `GET /api/links`
+    #[serde(default)]
+    tags: Vec<String>,
 }
 
 #[derive(Serialize)]
 struct ShortenResponse {
     short_url: String,
+    domain: String,
     original_url: String,
     created_at: String,
+    redirect_type: RedirectType,
+    passthrough: Passthrough,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qr: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
 }
 
 #[derive(Serialize)]
 struct AnalyticsResponse {
     short_code: String,
+    domain: String,
     original_url: String,
     created_at: String,
     transition_count: i64,
+    redirect_type: RedirectType,
+    passthrough: Passthrough,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<String>,
+    status: LinkStatus,
+    history: Vec<HistoryEntry>,
 }
 
 async fn health_check() -> impl Responder {
@@ -63,18 +203,100 @@ async fn health_check() -> impl Responder {
 }
 
 async fn db_health(_client: web::Data<Client>) -> impl Responder {
-    // REMOVE admin database ping
     HttpResponse::Ok().body("DB OK")
 }
 
 async fn ensure_indexes(client: &Client) {
     // Unique index on short_code in urls collection (prevents duplicate short codes)
     let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
+    // Short codes are unique per domain; the old global index would reject the same code on another domain
+    let _ = collection.drop_index("short_code_1", None).await;
     let index_model = IndexModel::builder()
-        .keys(doc! {"short_code": 1})
+        .keys(doc! {"domain": 1, "short_code": 1})
         .options(IndexOptions::builder().unique(true).build())
         .build();
     let _ = collection.create_index(index_model, None).await;
+    // Lets the purger find expired soft-deleted links
+    let deleted_index = IndexModel::builder()
+        .keys(doc! {"deleted_at": 1})
+        .options(IndexOptions::builder().sparse(true).build())
+        .build();
+    let _ = collection.create_index(deleted_index, None).await;
+    // Owner listings and analytics checks
+    let owner_index = IndexModel::builder()
+        .keys(doc! {"owner_id": 1, "created_at": -1})
+        .options(None)
+        .build();
+    let _ = collection.create_index(owner_index, None).await;
+    // `GET /api/links` sorts by clicks within an owner or workspace, and by date within a workspace
+    let owner_clicks_index = IndexModel::builder()
+        .keys(doc! {"owner_id": 1, "transition_count": -1, "_id": -1})
+        .options(None)
+        .build();
+    let _ = collection.create_index(owner_clicks_index, None).await;
+    let workspace_index = IndexModel::builder()
+        .keys(doc! {"workspace_id": 1, "created_at": -1, "_id": -1})
+        .options(IndexOptions::builder().partial_filter_expression(doc! {"workspace_id": {"$exists": true}}).build())
+        .build();
+    let _ = collection.create_index(workspace_index, None).await;
+    let workspace_clicks_index = IndexModel::builder()
+        .keys(doc! {"workspace_id": 1, "transition_count": -1, "_id": -1})
+        .options(IndexOptions::builder().partial_filter_expression(doc! {"workspace_id": {"$exists": true}}).build())
+        .build();
+    let _ = collection.create_index(workspace_clicks_index, None).await;
+    let tags_index = IndexModel::builder()
+        .keys(doc! {"owner_id": 1, "tags": 1, "created_at": -1})
+        .options(None)
+        .build();
+    let _ = collection.create_index(tags_index, None).await;
+    // API keys are looked up by their public prefix
+    let api_keys_collection: Collection<api_keys::ApiKeyDoc> = client.database("shortener").collection("api_keys");
+    let prefix_index = IndexModel::builder()
+        .keys(doc! {"prefix": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let _ = api_keys_collection.create_index(prefix_index, None).await;
+    let users_collection: Collection<accounts::UserDoc> = client.database("shortener").collection("users");
+    let email_index = IndexModel::builder()
+        .keys(doc! {"email": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let _ = users_collection.create_index(email_index, None).await;
+    // Sessions are looked up by token hash and removed by MongoDB once expired
+    let sessions_collection: Collection<accounts::SessionDoc> = client.database("shortener").collection("sessions");
+    let token_index = IndexModel::builder()
+        .keys(doc! {"token_hash": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let _ = sessions_collection.create_index(token_index, None).await;
+    let expiry_index = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(IndexOptions::builder().expire_after(std::time::Duration::ZERO).build())
+        .build();
+    let _ = sessions_collection.create_index(expiry_index, None).await;
+    let session_user_index = IndexModel::builder().keys(doc! {"user_id": 1}).options(None).build();
+    let _ = sessions_collection.create_index(session_user_index, None).await;
+    let banned_collection: Collection<admin::BannedDomainDoc> = client.database("shortener").collection("banned_domains");
+    let banned_index = IndexModel::builder()
+        .keys(doc! {"domain": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let _ = banned_collection.create_index(banned_index, None).await;
+    // Role checks and workspace listings look workspaces up by member
+    let workspaces_collection: Collection<workspaces::WorkspaceDoc> = client.database("shortener").collection("workspaces");
+    let member_index = IndexModel::builder().keys(doc! {"members.user_id": 1}).options(None).build();
+    let _ = workspaces_collection.create_index(member_index, None).await;
+    let invitations_collection: Collection<workspaces::InvitationDoc> = client.database("shortener").collection("invitations");
+    let invitation_token_index = IndexModel::builder()
+        .keys(doc! {"token_hash": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let _ = invitations_collection.create_index(invitation_token_index, None).await;
+    let invitation_expiry_index = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(IndexOptions::builder().expire_after(std::time::Duration::ZERO).build())
+        .build();
+    let _ = invitations_collection.create_index(invitation_expiry_index, None).await;
 
     // Indexes for analytics collection
     let analytics_collection: Collection<AnalyticsDoc> = client.database("shortener").collection("analytics");
@@ -93,67 +315,150 @@ async fn ensure_indexes(client: &Client) {
     let _ = analytics_collection.create_index(compound_index, None).await;
 }
 
+/// PNG QR code of `short_url` with the configured defaults, when the shorten request asked for one
+fn qr_data_uri(wanted: bool, options: &QrOptions, short_url: &str) -> Result<Option<String>> {
+    if !wanted {
+        return Ok(None);
+    }
+    let uri = qr::png_data_uri(short_url, options).map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
+    Ok(Some(uri))
+}
+
+/// Validate a destination, merge its UTM tags and normalize it; the error is the response body
+fn prepare_destination(url_service: &UrlService, url: &str, utm: &UtmParams, template: &UtmParams) -> Result<String, String> {
+    if let Err(e) = url_service.validate_url(url) {
+        debug!("URL validation failed: {:?}", e);
+        return Err(format!("Invalid URL: {}", e));
+    }
+    let tagged_url = utm.apply(url, template).map_err(|e| format!("Invalid URL: {}", e))?;
+    // Tags can push the URL over the length limit
+    if let Err(e) = url_service.validate_url(&tagged_url) {
+        debug!("URL validation failed: {:?}", e);
+        return Err(format!("Invalid URL: {}", e));
+    }
+    url_service.normalize_url(&tagged_url).map_err(|e| {
+        debug!("URL normalization failed: {:?}", e);
+        format!("URL normalization failed: {}", e)
+    })
+}
+
+/// A random 7 character code; callers retry on the rare collision
+fn generate_code() -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(7).map(char::from).collect()
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn shorten_url(
     client: web::Data<Client>,
+    settings: web::Data<Config>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    url_service: web::Data<UrlService>,
+    qr_options: web::Data<QrOptions>,
+    cache: web::Data<RedirectCache>,
+    code_filter: web::Data<CodeFilter>,
+    api_key: Option<web::ReqData<ApiKey>>,
+    user: Option<web::ReqData<User>>,
     req: web::Json<ShortenRequest>,
     http_req: actix_web::HttpRequest,
 ) -> Result<HttpResponse> {
+    // Logged-in users need no API key
+    let api_key = api_keys::authorize(api_key.as_deref(), Scope::Write, settings.auth.require_api_key && user.is_none())?;
+    let workspace_id = match &req.workspace_id {
+        Some(id) => {
+            Some(workspaces::authorize_shorten(&client, id, user.as_deref(), api_key.map(|key| key.owner_id.as_str())).await?)
+        }
+        None => None,
+    };
+    // Workspace links belong to the workspace, not to whoever created them
+    let owner_id = workspace_id.map(|id| id.to_hex()).or_else(|| caller_owner(user.as_deref(), api_key));
+    let domain = match domains.by_name(req.domain.as_deref()) {
+        Ok(domain) => domain,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
     // --- Integrate advanced validation and normalization ---
-    let url_service = UrlService::new_dummy();
-    if let Err(e) = url_service.validate_url(&req.url) {
-        println!("URL validation failed: {:?}", e);
-        return Ok(HttpResponse::BadRequest().body(format!("Invalid URL: {}", e)));
-    }
-    let normalized_url = match url_service.normalize_url(&req.url) {
+    let normalized_url = match prepare_destination(&url_service, &req.url, &req.utm, settings.utm.template_for(owner_id.as_deref())) {
         Ok(url) => url,
-        Err(e) => {
-            println!("URL normalization failed: {:?}", e);
-            return Ok(HttpResponse::BadRequest().body(format!("URL normalization failed: {}", e)));
-        }
+        Err(message) => return Ok(HttpResponse::BadRequest().body(message)),
     };
+    admin::check_destination(&client, &normalized_url).await?;
+    if let Err(e) = url_service.check_resolved_url(&normalized_url).await {
+        return Ok(HttpResponse::BadRequest().body(e.to_string()));
+    }
+    let tags = listing::normalize_tags(&req.tags)?;
+    let redirect_type = req.redirect_type.unwrap_or(settings.redirects.default_type);
+    let passthrough = req.passthrough.unwrap_or(settings.redirects.default_passthrough);
     let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
-    // Check if a short link already exists for this normalized URL
-    if let Some(existing) = collection.find_one(doc! {"original_url": &normalized_url}, None).await.map_err(|e| {
+    // Check if a short link already exists for this normalized URL on the requested domain
+    let existing_filter = doc! {
+        "original_url": &normalized_url,
+        "domain": domain.filter_value(),
+        "redirect_type": redirect_type.filter_value(),
+        "passthrough": passthrough.filter_value(),
+        // Disabled or deleted links are never handed out again
+        "status": null,
+        // Nor are other owners' links, whose analytics the caller could not read
+        "owner_id": owner_id.as_deref(),
+        "tags": if tags.is_empty() { Bson::Null } else { Bson::from(tags.clone()) },
+    };
+    if let Some(existing) = collection.find_one(existing_filter, None).await.map_err(|e| {
         actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e))
     })? {
-        let host = http_req.connection_info().host().to_string();
-        let scheme = if host.starts_with("localhost") || host.starts_with("127.0.0.1") { "http" } else { "https" };
-        let short_url = format!("{}://{}/{}", scheme, host, existing.short_code);
+        let short_url = domain.short_url(&public_url, &http_req, &existing.short_code);
         let created_at_rfc3339 = DateTime::<Utc>::from_timestamp_millis(existing.created_at.timestamp_millis()).unwrap().to_rfc3339();
         return Ok(HttpResponse::Ok().json(ShortenResponse {
+            qr: qr_data_uri(req.qr, &qr_options, &short_url)?,
             short_url,
+            domain: domain.name.clone(),
             original_url: normalized_url,
             created_at: created_at_rfc3339,
+            redirect_type,
+            passthrough,
+            tags,
         }));
     }
     // --- End integration ---
     let mut last_err = None;
     for _ in 0..5 {
-        let short_code: String = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(7)
-            .map(char::from)
-            .collect();
+        let short_code = generate_code();
         let now = MongoDateTime::now();
         let url_doc = UrlDoc {
             id: None,
             short_code: short_code.clone(),
+            domain: domain.stored_name(),
             original_url: normalized_url.clone(),
             created_at: now,
             transition_count: 0,
+            redirect_type,
+            passthrough,
+            updated_at: None,
+            history: Vec::new(),
+            status: LinkStatus::Active,
+            disabled_by: None,
+            deleted_at: None,
+            created_by: api_key.map(|key| key.id),
+            owner_id: owner_id.clone(),
+            workspace_id,
+            tags: tags.clone(),
         };
         let insert_result = collection.insert_one(&url_doc, None).await;
         match insert_result {
             Ok(_) => {
-                // Build the full short URL using the host header
-                let host = http_req.connection_info().host().to_string();
-                let scheme = if host.starts_with("localhost") || host.starts_with("127.0.0.1") { "http" } else { "https" };
-                let short_url = format!("{}://{}/{}", scheme, host, short_code);
+                // The code may have been cached as unknown before it existed
+                cache.invalidate(&domain.name, &short_code);
+                code_filter.insert(domain.stored_name().as_deref(), &short_code);
+                // Build the full short URL from the configured public base URL
+                let short_url = domain.short_url(&public_url, &http_req, &short_code);
                 let created_at_rfc3339 = DateTime::<Utc>::from_timestamp_millis(now.timestamp_millis()).unwrap().to_rfc3339();
                 return Ok(HttpResponse::Ok().json(ShortenResponse {
+                    qr: qr_data_uri(req.qr, &qr_options, &short_url)?,
                     short_url,
+                    domain: domain.name.clone(),
                     original_url: normalized_url.clone(),
                     created_at: created_at_rfc3339,
+                    redirect_type,
+                    passthrough,
+                    tags,
                 }));
             }
             Err(e) => {
@@ -172,121 +477,317 @@ async fn shorten_url(
     Err(actix_web::error::ErrorInternalServerError(format!("Failed to generate unique short code after 5 attempts: {:?}", last_err)))
 }
 
+#[derive(Deserialize)]
+struct RedirectPath {
+    short_code: String,
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn redirect_short_url(
     client: web::Data<Client>,
-    path: web::Path<String>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    cache: web::Data<RedirectCache>,
+    clicks: web::Data<ClickBuffer>,
+    code_filter: web::Data<CodeFilter>,
+    inactive: web::Data<InactivePage>,
+    path: web::Path<RedirectPath>,
+    http_req: actix_web::HttpRequest,
 ) -> Result<HttpResponse> {
-    let short_code = path.into_inner();
+    let short_code = path.into_inner().short_code;
+    let domain = domains.for_host(&public_url.request_host(&http_req));
     let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
-    let filter = doc! {"short_code": &short_code};
-    if let Some(mut url_doc) = collection.find_one(filter.clone(), None).await.map_err(|e| {
-        actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e))
-    })? {
-        // Increment transition count
-        url_doc.transition_count += 1;
-        collection.update_one(
-            doc! {"_id": &url_doc.id},
-            doc! {"$set": {"transition_count": url_doc.transition_count}},
-            None,
-        ).await.ok();
-        Ok(HttpResponse::Found().append_header(("Location", url_doc.original_url)).finish())
+    let link = match cache.get(&domain.name, &short_code) {
+        Lookup::Hit(link) => Some(link),
+        Lookup::NegativeHit => None,
+        // Definitely unknown codes (e.g. scanners) never reach the database
+        Lookup::Miss if !code_filter.might_exist(domain.stored_name().as_deref(), &short_code) => None,
+        Lookup::Miss => {
+            let filter = doc! {"short_code": &short_code, "domain": domain.filter_value()};
+            let link = collection.find_one(filter, None).await.map_err(|e| {
+                actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e))
+            })?.and_then(|url_doc| {
+                Some(CachedLink {
+                    id: url_doc.id?,
+                    original_url: url_doc.original_url,
+                    redirect_type: url_doc.redirect_type,
+                    passthrough: url_doc.passthrough,
+                    status: url_doc.status,
+                })
+            });
+            cache.insert(&domain.name, &short_code, link.clone());
+            link
+        }
+    };
+    if let Some(link) = link {
+        if !link.status.is_active() {
+            return Ok(inactive.response());
+        }
+        // Raw, still percent-encoded path after `/{short_code}/`, if any
+        let tail = http_req.uri().path().splitn(3, '/').nth(2);
+        let location = match link.passthrough.apply(&link.original_url, tail, http_req.query_string()) {
+            Ok(location) => location,
+            Err(PassthroughError::PathNotForwarded) => return Ok(HttpResponse::NotFound().body("Short URL not found")),
+            Err(e @ PassthroughError::DotSegment) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+            Err(e) => return Err(actix_web::error::ErrorInternalServerError(e.to_string())),
+        };
+        // Increment transition count (immediately or via the write-behind buffer); HEAD probes are not clicks
+        if http_req.method() != actix_web::http::Method::HEAD {
+            clicks.record(&client.database("shortener"), link.id).await;
+        }
+        Ok(HttpResponse::build(link.redirect_type.status()).append_header(("Location", location)).finish())
     } else {
         Ok(HttpResponse::NotFound().body("Short URL not found"))
     }
 }
 
+#[derive(Deserialize)]
+struct DomainQuery {
+    domain: Option<String>,
+}
+
 async fn analytics(
     client: web::Data<Client>,
+    settings: web::Data<Config>,
+    domains: web::Data<Domains>,
+    api_key: Option<web::ReqData<ApiKey>>,
+    user: Option<web::ReqData<User>>,
     path: web::Path<String>,
+    query: web::Query<DomainQuery>,
 ) -> Result<HttpResponse> {
+    let api_key = api_keys::authorize(api_key.as_deref(), Scope::Read, settings.auth.require_api_key && user.is_none())?;
     let short_code = path.into_inner();
+    let domain = match domains.by_name(query.domain.as_deref()) {
+        Ok(domain) => domain,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
     let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
-    let filter = doc! {"short_code": &short_code};
-    if let Some(url_doc) = collection.find_one(filter, None).await.map_err(|e| {
+    let filter = doc! {"short_code": &short_code, "domain": domain.filter_value()};
+    let url_doc = collection.find_one(filter, None).await.map_err(|e| {
         actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e))
-    })? {
+    })?;
+    let url_doc = match url_doc {
+        Some(url_doc) if can_read(&client, &url_doc, user.as_deref(), api_key).await? => Some(url_doc),
+        _ => None,
+    };
+    if let Some(url_doc) = url_doc {
         let created_at_rfc3339 = DateTime::<Utc>::from_timestamp_millis(url_doc.created_at.timestamp_millis()).unwrap().to_rfc3339();
         Ok(HttpResponse::Ok().json(AnalyticsResponse {
             short_code: url_doc.short_code,
+            domain: domain.name.clone(),
             original_url: url_doc.original_url,
             created_at: created_at_rfc3339,
             transition_count: url_doc.transition_count,
+            redirect_type: url_doc.redirect_type,
+            passthrough: url_doc.passthrough,
+            updated_at: url_doc.updated_at.map(links::rfc3339),
+            status: url_doc.status,
+            history: links::history_entries(&url_doc.history),
         }))
     } else {
         Ok(HttpResponse::NotFound().body("Short URL not found"))
     }
 }
 
+async fn list_domains(domains: web::Data<Domains>) -> impl Responder {
+    HttpResponse::Ok().json(domains.all())
+}
+
+async fn connect(config: &config::Config) -> Client {
+    // Configure client options with pooling
+    let mut client_options = ClientOptions::parse(&config.mongodb.uri).await.expect("Failed to parse MongoDB URI");
+    client_options.max_pool_size = Some(config.mongodb.max_pool_size);
+    client_options.min_pool_size = Some(config.mongodb.min_pool_size);
+    client_options.max_idle_time = Some(std::time::Duration::from_millis(config.mongodb.max_idle_time_ms));
+    client_options.connect_timeout = Some(std::time::Duration::from_millis(config.mongodb.connect_timeout_ms));
+    // Optionally set server API version for compatibility
+    client_options.server_api = Some(ServerApi::builder().version(ServerApiVersion::V1).build());
+    // The MongoDB Client object manages a pool of connections automatically
+    Client::with_options(client_options).expect("Failed to connect to MongoDB")
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    dotenvy::dotenv().ok();
+    let cli = match cli::Cli::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("{}\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    let config = match config::Config::load(cli.config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if cli.command == cli::Command::ConfigPrint {
+        print!("{}", config.to_redacted_toml());
+        return Ok(());
+    }
+    if let cli::Command::Import(args) = &cli.command {
+        let client = connect(&config).await;
+        ensure_indexes(&client).await;
+        if let Err(e) = importers::run(&client, &config, args).await {
+            eprintln!("Import failed: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     logging::set_panic_hook();
-    logging::init_logging_with_fallback();
-    if let Err(e) = tracing::init_tracer() {
+    logging::init_logging_with_fallback(config.is_development());
+    if let Err(e) = tracing::init_tracer(&config.tracing) {
         error!("Failed to initialize tracer: {:?}", e);
     }
-    dotenvy::dotenv().ok();
-    let mongo_uri = env::var("MONGODB_URI").unwrap_or_else(|_| "mongodb://mongo:27017".to_string());
-    // Pool settings from environment variables (with defaults)
-    let max_pool_size = env::var("MONGODB_MAX_POOL_SIZE").ok().and_then(|v| v.parse().ok()).unwrap_or(20);
-    let min_pool_size = env::var("MONGODB_MIN_POOL_SIZE").ok().and_then(|v| v.parse().ok()).unwrap_or(0);
-    let max_idle_time = env::var("MONGODB_MAX_IDLE_TIME_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(300000); // ms
-    let connect_timeout = env::var("MONGODB_CONNECT_TIMEOUT_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(10000); // ms
-    // Configure client options with pooling
-    let mut client_options = ClientOptions::parse(&mongo_uri).await.expect("Failed to parse MongoDB URI");
-    client_options.max_pool_size = Some(max_pool_size);
-    client_options.min_pool_size = Some(min_pool_size);
-    client_options.max_idle_time = Some(std::time::Duration::from_millis(max_idle_time));
-    client_options.connect_timeout = Some(std::time::Duration::from_millis(connect_timeout));
-    // Optionally set server API version for compatibility
-    client_options.server_api = Some(ServerApi::builder().version(ServerApiVersion::V1).build());
-    // The MongoDB Client object manages a pool of connections automatically
-    let client = Client::with_options(client_options).expect("Failed to connect to MongoDB");
+    let client = connect(&config).await;
     ensure_indexes(&client).await;
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allowed_origin("http://localhost:3000")
-            .allowed_origin("http://frontend:80")
-            .allow_any_method()
-            .allow_any_header()
-            .supports_credentials();
+    let public_url = web::Data::new(PublicUrl::from_config(&config.public).expect("Invalid public URL configuration"));
+    let domains = web::Data::new(Domains::from_config(&config.public).expect("Invalid short domain configuration"));
+    let settings = web::Data::new(config.clone());
+    let url_service = web::Data::new(UrlService::from_config(&config.links));
+    let jwt_verifier = jwt::JwtVerifier::from_config(&config.jwt).expect("Invalid JWT configuration").map(Arc::new);
+    let inactive_page = web::Data::new(InactivePage::from_config(&config.links).expect("Invalid inactive link configuration"));
+    links::spawn_purger(client.database("shortener"), &config.links);
+    let qr_options = web::Data::new(QrOptions::from_config(&config.qr).expect("Invalid QR code configuration"));
+    let redirect_cache = web::Data::new(RedirectCache::from_config(&config.cache));
+    let click_buffer = Arc::new(ClickBuffer::from_config(&config.clicks));
+    if click_buffer.is_buffered() {
+        click_buffer.spawn_flusher(client.database("shortener"));
+    }
+    let cors_policy = cors::CorsPolicy::from_config(&config.cors).expect("Invalid CORS configuration");
+    // Runs after the server has stopped accepting connections and drained in-flight requests
+    let mut shutdown_hooks = shutdown::ShutdownHooks::new();
+    let code_filter = Arc::new(CodeFilter::from_config(&config.bloom_filter));
+    if code_filter.is_enabled() {
+        code_filter.spawn_loader(client.database("shortener"));
+    }
+    let code_filter = web::Data::from(code_filter);
+    let (drain_buffer, drain_db) = (click_buffer.clone(), client.database("shortener"));
+    shutdown_hooks.register("clicks", move || async move { drain_buffer.drain(&drain_db).await });
+    shutdown_hooks.register("tracer", tracing::shutdown_tracer);
+    let click_data = web::Data::from(click_buffer.clone());
+    let result = HttpServer::new(move || {
         App::new()
-            .wrap(cors)
             .wrap(TracingLogger::default())
+            .wrap(api_keys::ApiKeyMiddleware)
+            .wrap(accounts::SessionMiddleware)
+            .wrap(jwt::JwtMiddleware::new(jwt_verifier.clone()))
             .wrap(logging::RequestIdMiddleware)
             .app_data(web::Data::new(client.clone()))
-            // REMOVE all /api/admin routes and admin_auth middleware
+            .app_data(settings.clone())
+            .app_data(public_url.clone())
+            .app_data(domains.clone())
+            .app_data(url_service.clone())
+            .app_data(redirect_cache.clone())
+            .app_data(click_data.clone())
+            .app_data(code_filter.clone())
+            .app_data(qr_options.clone())
+            .app_data(inactive_page.clone())
             .route("/health", web::get().to(health_check))
             .route("/db_health", web::get().to(db_health))
-            .route("/api/shorten", web::post().to(shorten_url))
-            .route("/api/analytics/{short_code}", web::get().to(analytics))
+            .route("/metrics", web::get().to(metrics::metrics))
+            // CORS applies to the API only; redirects are plain navigations and stay exempt
+            .service(
+                web::scope("/api")
+                    .wrap(cors_policy.middleware())
+                    .route("/shorten", web::post().to(shorten_url))
+                    .route("/shorten/batch", web::post().to(batch::shorten_batch))
+                    .route("/analytics/{short_code}", web::get().to(analytics))
+                    .route("/qr/{short_code}", web::get().to(qr::qr_code))
+                    .route("/links", web::get().to(listing::list_links))
+                    .route("/links/{short_code}", web::patch().to(links::update_link))
+                    .route("/links/{short_code}", web::delete().to(links::delete_link))
+                    .route("/links/{short_code}/disable", web::post().to(links::disable_link))
+                    .route("/links/{short_code}/enable", web::post().to(links::enable_link))
+                    .route("/auth/register", web::post().to(accounts::register))
+                    .route("/auth/login", web::post().to(accounts::login))
+                    .route("/auth/logout", web::post().to(accounts::logout))
+                    .route("/auth/me", web::get().to(accounts::me))
+                    .route("/auth/password", web::post().to(accounts::change_password))
+                    .route("/workspaces", web::post().to(workspaces::create_workspace))
+                    .route("/workspaces", web::get().to(workspaces::list_workspaces))
+                    .route("/workspaces/{id}", web::get().to(workspaces::get_workspace))
+                    .route("/workspaces/{id}/members/{user_id}", web::patch().to(workspaces::update_member))
+                    .route("/workspaces/{id}/members/{user_id}", web::delete().to(workspaces::remove_member))
+                    .route("/workspaces/{id}/invitations", web::post().to(workspaces::create_invitation))
+                    .route("/invitations/accept", web::post().to(workspaces::accept_invitation))
+                    .route("/keys", web::post().to(api_keys::create_key))
+                    .route("/keys", web::get().to(api_keys::list_keys))
+                    .route("/keys/{prefix}", web::delete().to(api_keys::revoke_key))
+                    .route("/domains", web::get().to(list_domains))
+                    .route("/admin/links", web::get().to(admin::search_links))
+                    .route("/admin/links/{short_code}/disable", web::post().to(admin::disable_link))
+                    .route("/admin/links/{short_code}/enable", web::post().to(admin::enable_link))
+                    .route("/admin/links/{short_code}", web::delete().to(admin::delete_link))
+                    .service(
+                        web::resource("/admin/import")
+                            .app_data(web::PayloadConfig::new(link_csv::MAX_IMPORT_BYTES))
+                            .route(web::post().to(link_csv::import_links)),
+                    )
+                    .route("/admin/export", web::get().to(link_csv::export_links))
+                    .route("/admin/banned-domains", web::post().to(admin::ban_domain))
+                    .route("/admin/banned-domains", web::get().to(admin::list_banned_domains))
+                    .route("/admin/banned-domains/{domain}", web::delete().to(admin::unban_domain))
+                    .route("/admin/stats", web::get().to(admin::stats))
+            )
+            .route("/{short_code}+", web::get().to(preview::preview_short_url))
+            .route("/{short_code}/preview", web::get().to(preview::preview_short_url))
             .route("/{short_code}", web::get().to(redirect_short_url))
+            .route("/{short_code}", web::head().to(redirect_short_url))
+            // Registered after the preview route, so `/preview` is never forwarded
+            .route("/{short_code}/{tail:.*}", web::get().to(redirect_short_url))
+            .route("/{short_code}/{tail:.*}", web::head().to(redirect_short_url))
     })
-    .bind(("0.0.0.0", 8080))?
+    .bind((config.server.host.as_str(), config.server.port))?
+    .shutdown_timeout(config.server.shutdown_timeout_secs)
     .run()
-    .await
+    .await;
+    info!("HTTP server stopped, draining pending work");
+    if !shutdown_hooks.run(std::time::Duration::from_secs(config.server.shutdown_timeout_secs)).await {
+        error!("Shutdown deadline exceeded, some pending work was dropped");
+        click_buffer.log_unflushed();
+    }
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
     use actix_web::{test, web, App};
     use mongodb::{Client, options::ClientOptions};
     use serde_json::json;
     use std::env;
 
-    #[actix_rt::test]
-    async fn test_shorten_valid_url() {
+    /// App with the shared state every handler expects, built from `config`; tests add their routes
+    async fn test_app(
+        config: Config,
+    ) -> App<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse, Error = actix_web::Error, InitError = ()>> {
         let mongo_uri = env::var("MONGODB_TEST_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
-        let mut client_options = ClientOptions::parse(&mongo_uri).await.expect("Failed to parse MongoDB URI");
+        let client_options = ClientOptions::parse(&mongo_uri).await.expect("Failed to parse MongoDB URI");
         let client = Client::with_options(client_options).expect("Failed to connect to MongoDB");
+        App::new()
+            .app_data(web::Data::new(client))
+            .app_data(web::Data::new(PublicUrl::from_config(&config.public).unwrap()))
+            .app_data(web::Data::new(RedirectCache::new(0, Default::default(), Default::default())))
+            .app_data(web::Data::new(CodeFilter::new(false, 1, 0.01, Default::default())))
+            .app_data(web::Data::new(Domains::from_config(&config.public).unwrap()))
+            .app_data(web::Data::new(UrlService::new_dummy()))
+            .app_data(web::Data::new(QrOptions::from_config(&config.qr).unwrap()))
+            .app_data(web::Data::new(config))
+    }
+
+    #[actix_rt::test]
+    async fn test_shorten_valid_url() {
         let app = test::init_service(
-            App::new()
-                .app_data(web::Data::new(client))
+            test_app(Config::default()).await
                 .route("/api/shorten", web::post().to(shorten_url))
         ).await;
         let req = test::TestRequest::post()
             .uri("/api/shorten")
-            .set_json(&json!({"url": "https://example.com"}))
+            .set_json(json!({"url": "https://example.com"}))
             .to_request();
         let resp = test::call_service(&app, req).await;
         if !resp.status().is_success() {
@@ -295,22 +796,17 @@ mod tests {
             println!("test_shorten_valid_url failed: status = {:?}, body = {:?}", status, body);
             panic!("test_shorten_valid_url failed");
         }
-        assert!(true);
     }
 
     #[actix_rt::test]
     async fn test_shorten_invalid_url_format() {
-        let mongo_uri = env::var("MONGODB_TEST_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
-        let mut client_options = ClientOptions::parse(&mongo_uri).await.expect("Failed to parse MongoDB URI");
-        let client = Client::with_options(client_options).expect("Failed to connect to MongoDB");
         let app = test::init_service(
-            App::new()
-                .app_data(web::Data::new(client))
+            test_app(Config::default()).await
                 .route("/api/shorten", web::post().to(shorten_url))
         ).await;
         let req = test::TestRequest::post()
             .uri("/api/shorten")
-            .set_json(&json!({"url": "not_a_url"}))
+            .set_json(json!({"url": "not_a_url"}))
             .to_request();
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), 400);
@@ -318,17 +814,13 @@ mod tests {
 
     #[actix_rt::test]
     async fn test_shorten_disallowed_domain() {
-        let mongo_uri = env::var("MONGODB_TEST_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
-        let mut client_options = ClientOptions::parse(&mongo_uri).await.expect("Failed to parse MongoDB URI");
-        let client = Client::with_options(client_options).expect("Failed to connect to MongoDB");
         let app = test::init_service(
-            App::new()
-                .app_data(web::Data::new(client))
+            test_app(Config::default()).await
                 .route("/api/shorten", web::post().to(shorten_url))
         ).await;
         let req = test::TestRequest::post()
             .uri("/api/shorten")
-            .set_json(&json!({"url": "http://localhost"}))
+            .set_json(json!({"url": "http://localhost"}))
             .to_request();
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), 400);
@@ -336,20 +828,115 @@ mod tests {
 
     #[actix_rt::test]
     async fn test_shorten_url_too_long() {
-        let mongo_uri = env::var("MONGODB_TEST_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
-        let mut client_options = ClientOptions::parse(&mongo_uri).await.expect("Failed to parse MongoDB URI");
-        let client = Client::with_options(client_options).expect("Failed to connect to MongoDB");
         let app = test::init_service(
-            App::new()
-                .app_data(web::Data::new(client))
+            test_app(Config::default()).await
                 .route("/api/shorten", web::post().to(shorten_url))
         ).await;
         let long_url = format!("http://{}", "a".repeat(2050));
         let req = test::TestRequest::post()
             .uri("/api/shorten")
-            .set_json(&json!({"url": long_url}))
+            .set_json(json!({"url": long_url}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn test_shorten_unknown_domain() {
+        let mut config = Config::default();
+        config.public.domains = vec!["https://go.brand-a.com".into()];
+        let app = test::init_service(
+            test_app(config).await
+                .route("/api/shorten", web::post().to(shorten_url))
+        ).await;
+        let req = test::TestRequest::post()
+            .uri("/api/shorten")
+            .set_json(json!({"url": "https://example.com", "domain": "brand-b.link"}))
             .to_request();
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), 400);
     }
+
+    #[actix_rt::test]
+    async fn test_shorten_unsupported_redirect_type() {
+        let app = test::init_service(
+            test_app(Config::default()).await
+                .route("/api/shorten", web::post().to(shorten_url))
+        ).await;
+        let req = test::TestRequest::post()
+            .uri("/api/shorten")
+            .set_json(json!({"url": "https://example.com", "redirect_type": 303}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn test_update_link_requires_token() {
+        let mut config = Config::default();
+        config.auth.api_token = Some("0123456789abcdef".into());
+        let app = test::init_service(
+            test_app(config).await
+                .route("/api/links/{short_code}", web::patch().to(links::update_link))
+        ).await;
+        let req = test::TestRequest::patch()
+            .uri("/api/links/abc1234")
+            .set_json(json!({"url": "https://example.com/fixed"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+        let req = test::TestRequest::patch()
+            .uri("/api/links/abc1234")
+            .insert_header(("Authorization", "Bearer not-the-token"))
+            .set_json(json!({"url": "https://example.com/fixed"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+    }
+
+    #[actix_rt::test]
+    async fn test_shorten_requires_api_key_when_configured() {
+        let mut config = Config::default();
+        config.auth.require_api_key = true;
+        let app = test::init_service(
+            test_app(config).await
+                .wrap(api_keys::ApiKeyMiddleware)
+                .route("/api/shorten", web::post().to(shorten_url))
+        ).await;
+        let req = test::TestRequest::post()
+            .uri("/api/shorten")
+            .set_json(json!({"url": "https://example.com"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+        // Malformed keys are rejected by the middleware before any lookup
+        let req = test::TestRequest::post()
+            .uri("/api/shorten")
+            .insert_header(("X-API-Key", "not-a-key"))
+            .set_json(json!({"url": "https://example.com"}))
+            .to_request();
+        let err = test::try_call_service(&app, req).await.unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), 401);
+    }
+
+    #[actix_rt::test]
+    async fn test_owned_links_visible_to_owner_only() {
+        let mut url_doc: UrlDoc = mongodb::bson::from_document(doc! {
+            "short_code": "abc1234",
+            "original_url": "https://example.com",
+            "created_at": MongoDateTime::now(),
+            "transition_count": 3_i64,
+        })
+        .unwrap();
+        assert!(url_doc.is_visible_to(None));
+        url_doc.owner_id = Some("team-a".into());
+        let key = |owner: &str| ApiKey { id: ObjectId::new(), prefix: "lsk_abcd1234".into(), owner_id: owner.into(), scopes: vec![Scope::Read] };
+        assert!(url_doc.is_visible_to(caller_owner(None, Some(&key("team-a"))).as_deref()));
+        assert!(!url_doc.is_visible_to(caller_owner(None, Some(&key("team-b"))).as_deref()));
+        assert!(!url_doc.is_visible_to(None));
+        // A logged-in user acts for themselves, even with another owner's key
+        let user = User { id: ObjectId::new(), email: "ada@example.com".into(), session_id: ObjectId::new() };
+        url_doc.owner_id = Some(user.owner_id());
+        assert!(url_doc.is_visible_to(caller_owner(Some(&user), Some(&key("team-a"))).as_deref()));
+    }
 }
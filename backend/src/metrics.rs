@@ -1 +1,44 @@
-// metrics.rs is now empty because all metrics-related code was unused and has been removed.
+//! Metrics Module
+//!
+//! Exposes internal counters at `/metrics` in the Prometheus text format.
+
+use actix_web::{web, HttpResponse};
+use std::fmt::Write;
+
+use crate::bloom_filter::CodeFilter;
+use crate::click_buffer::ClickBuffer;
+use crate::redirect_cache::RedirectCache;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+pub async fn metrics(
+    cache: web::Data<RedirectCache>,
+    clicks: web::Data<ClickBuffer>,
+    code_filter: web::Data<CodeFilter>,
+) -> HttpResponse {
+    let mut out = String::new();
+    let stats = cache.stats();
+    metric(&mut out, "redirect_cache_capacity", "gauge", "Maximum number of cached redirects", stats.capacity);
+    metric(&mut out, "redirect_cache_entries", "gauge", "Cached redirects, including negative entries", stats.entries);
+    metric(&mut out, "redirect_cache_hits_total", "counter", "Redirects served from the cache", stats.hits);
+    metric(&mut out, "redirect_cache_negative_hits_total", "counter", "Unknown codes answered from the cache", stats.negative_hits);
+    metric(&mut out, "redirect_cache_misses_total", "counter", "Redirect lookups that went to the database", stats.misses);
+    metric(&mut out, "redirect_cache_evictions_total", "counter", "Entries evicted to make room", stats.evictions);
+    metric(&mut out, "redirect_cache_invalidations_total", "counter", "Entries dropped because the link changed", stats.invalidations);
+    let stats = clicks.stats();
+    metric(&mut out, "click_buffer_pending_links", "gauge", "Links with buffered click increments", stats.pending_links);
+    metric(&mut out, "click_buffer_pending_clicks", "gauge", "Clicks buffered but not yet written", stats.pending_clicks);
+    metric(&mut out, "click_buffer_flushed_clicks_total", "counter", "Buffered clicks written to the database", stats.flushed_clicks);
+    metric(&mut out, "click_buffer_failed_flushes_total", "counter", "Batch writes that failed and were retried", stats.failed_flushes);
+    metric(&mut out, "click_buffer_dropped_clicks_total", "counter", "Clicks dropped after their update kept failing", stats.dropped_clicks);
+    metric(&mut out, "bloom_filter_ready", "gauge", "Whether the short code Bloom filter is built", code_filter.is_ready() as u8);
+    metric(&mut out, "bloom_filter_items", "gauge", "Short codes in the Bloom filter", code_filter.items());
+    metric(&mut out, "bloom_filter_rejections_total", "counter", "Unknown codes answered without a database query", code_filter.rejections());
+    HttpResponse::Ok().content_type(CONTENT_TYPE).body(out)
+}
@@ -0,0 +1,159 @@
+//! Passthrough Module
+//!
+//! Lets a link forward what follows the short code: `/{short_code}/docs/page?x=1`
+//! can redirect to the destination with `/docs/page` appended and `x=1` merged
+//! into its query. Chosen per link; links created before this setting existed
+//! forward nothing.
+
+use mongodb::bson::{bson, Bson};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use thiserror::Error;
+use url::Url;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Passthrough {
+    /// Redirect to the destination as stored; extra path segments are a 404
+    #[default]
+    Off,
+    /// Append the extra path to the destination path
+    Path,
+    /// Merge the request query string into the destination query
+    Query,
+    PathAndQuery,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum PassthroughError {
+    #[error("This link does not forward paths")]
+    PathNotForwarded,
+    #[error("Invalid path: dot segments are not allowed")]
+    DotSegment,
+    #[error("Invalid destination URL: {0}")]
+    InvalidDestination(String),
+}
+
+impl Passthrough {
+    pub fn forwards_path(self) -> bool {
+        matches!(self, Passthrough::Path | Passthrough::PathAndQuery)
+    }
+
+    pub fn forwards_query(self) -> bool {
+        matches!(self, Passthrough::Query | Passthrough::PathAndQuery)
+    }
+
+    /// Value to match `passthrough` against in Mongo filters; `off` also matches links without the field
+    pub fn filter_value(self) -> Bson {
+        match self {
+            Passthrough::Off => bson!({"$in": ["off", Bson::Null]}),
+            other => Bson::String(other.as_str().to_string()),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Passthrough::Off => "off",
+            Passthrough::Path => "path",
+            Passthrough::Query => "query",
+            Passthrough::PathAndQuery => "path_and_query",
+        }
+    }
+
+    /// Build the redirect target from the stored destination, the raw (still
+    /// percent-encoded) path after the short code and the raw request query.
+    /// Destination query parameters win over request parameters with the same name.
+    pub fn apply(self, destination: &str, tail: Option<&str>, query: &str) -> Result<String, PassthroughError> {
+        let tail = tail.filter(|t| !t.is_empty());
+        if tail.is_some() && !self.forwards_path() {
+            return Err(PassthroughError::PathNotForwarded);
+        }
+        let query = if self.forwards_query() { query } else { "" };
+        if tail.is_none() && query.is_empty() {
+            return Ok(destination.to_string());
+        }
+        let mut url = Url::parse(destination).map_err(|e| PassthroughError::InvalidDestination(e.to_string()))?;
+        if let Some(tail) = tail {
+            if tail.split('/').any(is_dot_segment) {
+                return Err(PassthroughError::DotSegment);
+            }
+            let path = format!("{}/{}", url.path().trim_end_matches('/'), tail);
+            url.set_path(&path);
+        }
+        if !query.is_empty() {
+            let existing: HashSet<String> = url.query_pairs().map(|(k, _)| k.into_owned()).collect();
+            let extra: Vec<(String, String)> = url::form_urlencoded::parse(query.as_bytes())
+                .filter(|(k, _)| !existing.contains(k.as_ref()))
+                .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                .collect();
+            if !extra.is_empty() {
+                url.query_pairs_mut().extend_pairs(extra);
+            }
+        }
+        Ok(url.into())
+    }
+}
+
+/// `.` and `..`, including percent-encoded forms, which would climb out of the destination path
+fn is_dot_segment(segment: &str) -> bool {
+    let decoded = segment.to_ascii_lowercase().replace("%2e", ".");
+    decoded == "." || decoded == ".."
+}
+
+impl std::str::FromStr for Passthrough {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(Passthrough::Off),
+            "path" => Ok(Passthrough::Path),
+            "query" => Ok(Passthrough::Query),
+            "path_and_query" => Ok(Passthrough::PathAndQuery),
+            other => Err(format!("unknown passthrough mode {}, expected off, path, query or path_and_query", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_off_only_redirects_bare_codes() {
+        let destination = "https://example.com/base?a=1";
+        assert_eq!(Passthrough::Off.apply(destination, None, "x=1").unwrap(), destination);
+        assert_eq!(Passthrough::Off.apply(destination, Some("docs"), ""), Err(PassthroughError::PathNotForwarded));
+        assert_eq!(Passthrough::Query.apply(destination, Some("docs"), ""), Err(PassthroughError::PathNotForwarded));
+    }
+
+    #[test]
+    fn test_append_path() {
+        let target = Passthrough::Path.apply("https://example.com/base/?a=1", Some("docs/page%20one"), "x=1").unwrap();
+        assert_eq!(target, "https://example.com/base/docs/page%20one?a=1");
+        let target = Passthrough::Path.apply("https://example.com", Some("docs"), "").unwrap();
+        assert_eq!(target, "https://example.com/docs");
+    }
+
+    #[test]
+    fn test_merge_query() {
+        let target = Passthrough::Query.apply("https://example.com/p?a=1", None, "a=2&x=hello%20world").unwrap();
+        assert_eq!(target, "https://example.com/p?a=1&x=hello+world");
+        let target = Passthrough::PathAndQuery.apply("https://example.com/p", Some("q"), "x=1").unwrap();
+        assert_eq!(target, "https://example.com/p/q?x=1");
+    }
+
+    #[test]
+    fn test_dot_segments_rejected() {
+        for tail in ["..", "docs/../../admin", "%2E%2e/admin", "./x"] {
+            assert_eq!(Passthrough::Path.apply("https://example.com/base", Some(tail), ""), Err(PassthroughError::DotSegment));
+        }
+    }
+
+    #[test]
+    fn test_serde_and_parse() {
+        assert_eq!(serde_json::to_string(&Passthrough::PathAndQuery).unwrap(), "\"path_and_query\"");
+        assert_eq!(serde_json::from_str::<Passthrough>("\"query\"").unwrap(), Passthrough::Query);
+        assert_eq!("PATH".parse::<Passthrough>().unwrap(), Passthrough::Path);
+        assert!("both".parse::<Passthrough>().is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! Link Preview Module
+//!
+//! Shows where a short link points instead of redirecting, at
+//! `/{short_code}+` and `/{short_code}/preview`. Browsers get a minimal HTML
+//! page; clients sending `Accept: application/json` (or `?format=json`) get
+//! the same data as JSON. Previews do not count as clicks, and links with an
+//! owner do not show their click count.
+
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use mongodb::bson::doc;
+use mongodb::{Client, Collection};
+use serde::{Deserialize, Serialize};
+
+use crate::domains::Domains;
+use crate::links::InactivePage;
+use crate::public_url::PublicUrl;
+use crate::UrlDoc;
+
+/// The page has no scripts or external resources
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; style-src 'unsafe-inline'";
+
+#[derive(Serialize)]
+pub struct PreviewResponse {
+    short_code: String,
+    short_url: String,
+    domain: String,
+    original_url: String,
+    created_at: String,
+    /// Hidden for links with an owner, whose analytics are private
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transition_count: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct PreviewQuery {
+    format: Option<String>,
+}
+
+fn wants_json(req: &HttpRequest, query: &PreviewQuery) -> bool {
+    if let Some(format) = &query.format {
+        return format.eq_ignore_ascii_case("json");
+    }
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|accept| accept.contains("application/json") && !accept.contains("text/html"))
+        .unwrap_or(false)
+}
+
+/// Escape text for use in HTML element content and double-quoted attributes
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn render_html(preview: &PreviewResponse) -> String {
+    let destination = escape_html(&preview.original_url);
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta name="robots" content="noindex, nofollow">
+<title>Preview of {short_url}</title>
+<style>body{{font-family:system-ui,sans-serif;max-width:40rem;margin:3rem auto;padding:0 1rem;line-height:1.5}}code{{word-break:break-all}}dt{{font-weight:bold}}</style>
+</head>
+<body>
+<h1>Where does this link go?</h1>
+<p><code>{short_url}</code> redirects to:</p>
+<p><code>{destination}</code></p>
+<dl>
+<dt>Created</dt><dd>{created_at}</dd>{clicks}
+</dl>
+<p><a href="{destination}" rel="noopener noreferrer nofollow">Continue to the destination</a></p>
+</body>
+</html>
+"#,
+        short_url = escape_html(&preview.short_url),
+        destination = destination,
+        created_at = escape_html(&preview.created_at),
+        clicks = preview.transition_count.map(|count| format!("\n<dt>Clicks</dt><dd>{}</dd>", count)).unwrap_or_default(),
+    )
+}
+
+pub async fn preview_short_url(
+    client: web::Data<Client>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    inactive: web::Data<InactivePage>,
+    path: web::Path<String>,
+    query: web::Query<PreviewQuery>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse> {
+    let short_code = path.into_inner();
+    let domain = domains.for_host(&public_url.request_host(&http_req));
+    let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
+    let filter = doc! {"short_code": &short_code, "domain": domain.filter_value()};
+    let Some(url_doc) = collection.find_one(filter, None).await.map_err(|e| {
+        actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e))
+    })? else {
+        return Ok(HttpResponse::NotFound().body("Short URL not found"));
+    };
+    if !url_doc.status.is_active() {
+        return Ok(inactive.response());
+    }
+    let preview = PreviewResponse {
+        transition_count: url_doc.is_visible_to(None).then_some(url_doc.transition_count),
+        short_url: domain.short_url(&public_url, &http_req, &url_doc.short_code),
+        short_code: url_doc.short_code,
+        domain: domain.name.clone(),
+        original_url: url_doc.original_url,
+        created_at: DateTime::<Utc>::from_timestamp_millis(url_doc.created_at.timestamp_millis()).unwrap().to_rfc3339(),
+    };
+    if wants_json(&http_req, &query) {
+        return Ok(HttpResponse::Ok().json(preview));
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header((header::CONTENT_SECURITY_POLICY, HeaderValue::from_static(CONTENT_SECURITY_POLICY)))
+        .insert_header(("X-Robots-Tag", "noindex, nofollow"))
+        .body(render_html(&preview)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn preview(original_url: &str) -> PreviewResponse {
+        PreviewResponse {
+            short_code: "abc1234".into(),
+            short_url: "https://sho.rt/abc1234".into(),
+            domain: "sho.rt".into(),
+            original_url: original_url.into(),
+            created_at: "2026-01-01T00:00:00+00:00".into(),
+            transition_count: Some(42),
+        }
+    }
+
+    #[test]
+    fn test_html_escapes_destination() {
+        let html = render_html(&preview("https://example.com/?q=\"><script>alert(1)</script>"));
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&quot;&gt;&lt;script&gt;"));
+        assert!(html.contains("<dd>42</dd>"));
+        let private = PreviewResponse { transition_count: None, ..preview("https://example.com") };
+        assert!(!render_html(&private).contains("Clicks"));
+    }
+
+    #[test]
+    fn test_content_negotiation() {
+        let html = PreviewQuery { format: None };
+        let json = PreviewQuery { format: Some("JSON".into()) };
+        let browser = TestRequest::default()
+            .insert_header(("Accept", "text/html,application/xhtml+xml,application/json;q=0.9"))
+            .to_http_request();
+        let api = TestRequest::default().insert_header(("Accept", "application/json")).to_http_request();
+        assert!(!wants_json(&browser, &html));
+        assert!(wants_json(&api, &html));
+        assert!(wants_json(&browser, &json));
+        assert!(!wants_json(&TestRequest::default().to_http_request(), &html));
+    }
+}
@@ -0,0 +1,190 @@
+//! Public URL Module
+//!
+//! Builds the externally visible short URLs returned to clients. A configured
+//! canonical base URL always wins; without one the scheme and host are taken
+//! from the request, honouring `Forwarded`/`X-Forwarded-*` headers only when
+//! the deployment sits behind a trusted proxy.
+
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::HttpRequest;
+use thiserror::Error;
+use url::Url;
+
+use crate::config::PublicConfig;
+
+pub struct PublicUrl {
+    /// Canonical base (scheme, host and optional path prefix) without a trailing slash
+    base_url: Option<String>,
+    /// Whether `Forwarded`/`X-Forwarded-Proto`/`X-Forwarded-Host` may be used
+    trust_forwarded: bool,
+}
+
+impl PublicUrl {
+    pub fn new(base_url: Option<&str>, trust_forwarded: bool) -> Result<Self, PublicUrlError> {
+        let base_url = match base_url.map(str::trim).filter(|s| !s.is_empty()) {
+            Some(raw) => Some(Self::parse_base_url(raw)?),
+            None => None,
+        };
+        Ok(PublicUrl { base_url, trust_forwarded })
+    }
+
+    pub fn from_config(config: &PublicConfig) -> Result<Self, PublicUrlError> {
+        Self::new(config.base_url.as_deref(), config.trust_forwarded_headers)
+    }
+
+    /// Validate a base URL and return it without a trailing slash
+    pub fn parse_base_url(raw: &str) -> Result<String, PublicUrlError> {
+        let parsed = Url::parse(raw).map_err(|_| PublicUrlError::InvalidBaseUrl(raw.into()))?;
+        if parsed.scheme() != "http" && parsed.scheme() != "https" {
+            return Err(PublicUrlError::InvalidBaseUrl(raw.into()));
+        }
+        if parsed.host_str().is_none() || parsed.query().is_some() || parsed.fragment().is_some() {
+            return Err(PublicUrlError::InvalidBaseUrl(raw.into()));
+        }
+        Ok(parsed.as_str().trim_end_matches('/').to_string())
+    }
+
+    /// Full short URL for `short_code` as seen by clients of this request
+    pub fn short_url(&self, req: &HttpRequest, short_code: &str) -> String {
+        format!("{}/{}", self.base_for_request(req), short_code)
+    }
+
+    fn base_for_request(&self, req: &HttpRequest) -> String {
+        if let Some(base) = &self.base_url {
+            return base.clone();
+        }
+        let scheme = self
+            .forwarded_headers(req)
+            .and_then(forwarded_proto)
+            .unwrap_or_else(|| if req.app_config().secure() { "https".into() } else { "http".into() });
+        format!("{}://{}", scheme, self.request_host(req))
+    }
+
+    /// Host (with port, if any) the client addressed, honouring trusted forwarding headers
+    pub fn request_host(&self, req: &HttpRequest) -> String {
+        self.forwarded_headers(req)
+            .and_then(forwarded_host)
+            .or_else(|| req.headers().get(header::HOST).and_then(|v| v.to_str().ok()).map(str::to_string))
+            .unwrap_or_else(|| req.app_config().host().to_string())
+    }
+
+    fn forwarded_headers<'a>(&self, req: &'a HttpRequest) -> Option<&'a HeaderMap> {
+        if self.trust_forwarded { Some(req.headers()) } else { None }
+    }
+}
+
+/// Last element of a comma-separated header, which may be sent as several header lines.
+/// Proxies append to these headers, so earlier elements are whatever the client sent.
+fn last_element(headers: &HeaderMap, name: impl header::AsHeaderName) -> Option<&str> {
+    let value = headers.get_all(name).last()?.to_str().ok()?;
+    value.rsplit(',').next()
+}
+
+/// Value of a `Forwarded` header parameter (`proto`, `host`, ...) from the hop added by the trusted proxy
+fn forwarded_param(headers: &HeaderMap, name: &str) -> Option<String> {
+    let last_hop = last_element(headers, header::FORWARDED)?;
+    last_hop.split(';').find_map(|pair| {
+        let (key, val) = pair.trim().split_once('=')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(val.trim().trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
+fn x_forwarded(headers: &HeaderMap, name: &str) -> Option<String> {
+    last_element(headers, name).map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+fn forwarded_proto(headers: &HeaderMap) -> Option<String> {
+    forwarded_param(headers, "proto")
+        .or_else(|| x_forwarded(headers, "x-forwarded-proto"))
+        .map(|p| p.to_ascii_lowercase())
+        .filter(|p| p == "http" || p == "https")
+}
+
+fn forwarded_host(headers: &HeaderMap) -> Option<String> {
+    forwarded_param(headers, "host").or_else(|| x_forwarded(headers, "x-forwarded-host"))
+}
+
+#[derive(Debug, Error)]
+pub enum PublicUrlError {
+    #[error("Invalid public base URL: {0}")]
+    InvalidBaseUrl(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_configured_base_url_ignores_host_header() {
+        let public_url = PublicUrl::new(Some("https://sho.rt/"), false).unwrap();
+        let req = TestRequest::default()
+            .insert_header(("Host", "localhost:8080"))
+            .insert_header(("X-Forwarded-Proto", "http"))
+            .to_http_request();
+        assert_eq!(public_url.short_url(&req, "abc1234"), "https://sho.rt/abc1234");
+    }
+
+    #[test]
+    fn test_configured_base_url_keeps_path_prefix() {
+        let public_url = PublicUrl::new(Some("https://example.com/s"), false).unwrap();
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(public_url.short_url(&req, "abc1234"), "https://example.com/s/abc1234");
+    }
+
+    #[test]
+    fn test_invalid_base_url() {
+        assert!(PublicUrl::new(Some("ftp://example.com"), false).is_err());
+        assert!(PublicUrl::new(Some("not a url"), false).is_err());
+        assert!(PublicUrl::new(Some("https://example.com/?a=1"), false).is_err());
+    }
+
+    #[test]
+    fn test_untrusted_forwarded_headers_ignored() {
+        let public_url = PublicUrl::new(None, false).unwrap();
+        let req = TestRequest::default()
+            .insert_header(("Host", "sho.rt"))
+            .insert_header(("X-Forwarded-Proto", "https"))
+            .insert_header(("X-Forwarded-Host", "evil.example"))
+            .to_http_request();
+        assert_eq!(public_url.short_url(&req, "abc"), "http://sho.rt/abc");
+    }
+
+    #[test]
+    fn test_trusted_forwarded_headers() {
+        let public_url = PublicUrl::new(None, true).unwrap();
+        let req = TestRequest::default()
+            .insert_header(("Host", "backend:8080"))
+            .insert_header(("Forwarded", "for=1.2.3.4;proto=https;host=sho.rt"))
+            .to_http_request();
+        assert_eq!(public_url.short_url(&req, "abc"), "https://sho.rt/abc");
+
+        let req = TestRequest::default()
+            .insert_header(("Host", "sho.rt"))
+            .insert_header(("X-Forwarded-Proto", "https"))
+            .to_http_request();
+        assert_eq!(public_url.short_url(&req, "abc"), "https://sho.rt/abc");
+    }
+
+    #[test]
+    fn test_forwarded_headers_use_the_proxy_hop() {
+        let public_url = PublicUrl::new(None, true).unwrap();
+        // The first hop is what the client sent; the proxy appended the last one
+        let req = TestRequest::default()
+            .insert_header(("Host", "backend:8080"))
+            .insert_header(("Forwarded", "proto=http;host=evil.example, for=1.2.3.4;proto=https;host=sho.rt"))
+            .to_http_request();
+        assert_eq!(public_url.short_url(&req, "abc"), "https://sho.rt/abc");
+
+        let req = TestRequest::default()
+            .insert_header(("Host", "backend:8080"))
+            .insert_header(("X-Forwarded-Proto", "http, https"))
+            .insert_header(("X-Forwarded-Host", "evil.example, sho.rt"))
+            .to_http_request();
+        assert_eq!(public_url.short_url(&req, "abc"), "https://sho.rt/abc");
+    }
+}
@@ -0,0 +1,327 @@
+//! QR Code Module
+//!
+//! Renders short URLs as QR codes, as PNG or SVG, at `/api/qr/{short_code}`.
+//! Size, error correction, margin and colours default to the `[qr]` config
+//! section and can be overridden per request. Codes are encoded locally; no
+//! external service sees the links.
+
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use base64::Engine;
+use mongodb::bson::doc;
+use mongodb::{Client, Collection};
+use qrcode::{Color, EcLevel, QrCode};
+use serde::Deserialize;
+use std::fmt::Write;
+use thiserror::Error;
+
+use crate::config::QrConfig;
+use crate::domains::Domains;
+use crate::public_url::PublicUrl;
+use crate::UrlDoc;
+
+const MIN_SIZE: u32 = 64;
+const MAX_SIZE: u32 = 2048;
+const MAX_MARGIN: u32 = 16;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum QrError {
+    #[error("Invalid format '{0}', expected png or svg")]
+    InvalidFormat(String),
+    #[error("Invalid size {0}, expected {MIN_SIZE} to {MAX_SIZE} pixels")]
+    InvalidSize(u32),
+    #[error("Invalid error correction level '{0}', expected L, M, Q or H")]
+    InvalidEcLevel(String),
+    #[error("Invalid margin {0}, expected at most {MAX_MARGIN} modules")]
+    InvalidMargin(u32),
+    #[error("Invalid colour '{0}', expected a hex colour such as #1a2b3c")]
+    InvalidColor(String),
+    #[error("Failed to encode QR code: {0}")]
+    Encode(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QrFormat {
+    Png,
+    Svg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rgb([u8; 3]);
+
+impl Rgb {
+    /// `#rrggbb` or `#rgb`, the `#` being optional
+    pub fn parse(value: &str) -> Result<Self, QrError> {
+        let hex = value.trim().trim_start_matches('#');
+        let invalid = || QrError::InvalidColor(value.to_string());
+        let expanded: String = match hex.len() {
+            3 => hex.chars().flat_map(|c| [c, c]).collect(),
+            6 => hex.to_string(),
+            _ => return Err(invalid()),
+        };
+        let mut rgb = [0u8; 3];
+        for (i, channel) in rgb.iter_mut().enumerate() {
+            *channel = u8::from_str_radix(expanded.get(i * 2..i * 2 + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid())?;
+        }
+        Ok(Rgb(rgb))
+    }
+
+    fn hex(self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.0[0], self.0[1], self.0[2])
+    }
+}
+
+fn parse_ec_level(value: &str) -> Result<EcLevel, QrError> {
+    match value.trim().to_ascii_uppercase().as_str() {
+        "L" => Ok(EcLevel::L),
+        "M" => Ok(EcLevel::M),
+        "Q" => Ok(EcLevel::Q),
+        "H" => Ok(EcLevel::H),
+        _ => Err(QrError::InvalidEcLevel(value.to_string())),
+    }
+}
+
+/// Per-request overrides; anything missing comes from the config
+#[derive(Debug, Default, Deserialize)]
+pub struct QrQuery {
+    domain: Option<String>,
+    format: Option<String>,
+    size: Option<u32>,
+    ec: Option<String>,
+    margin: Option<u32>,
+    fg: Option<String>,
+    bg: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct QrOptions {
+    pub format: QrFormat,
+    /// Requested width in pixels; the image is rounded down to whole pixels per module
+    pub size: u32,
+    pub ec_level: EcLevel,
+    /// Quiet zone in modules
+    pub margin: u32,
+    pub foreground: Rgb,
+    pub background: Rgb,
+}
+
+impl QrOptions {
+    pub fn from_config(config: &QrConfig) -> Result<Self, QrError> {
+        let options = QrOptions {
+            format: QrFormat::Png,
+            size: config.size,
+            ec_level: parse_ec_level(&config.error_correction)?,
+            margin: config.margin,
+            foreground: Rgb::parse(&config.foreground)?,
+            background: Rgb::parse(&config.background)?,
+        };
+        options.check()
+    }
+
+    fn with_query(mut self, query: &QrQuery) -> Result<Self, QrError> {
+        if let Some(format) = &query.format {
+            self.format = match format.to_ascii_lowercase().as_str() {
+                "png" => QrFormat::Png,
+                "svg" => QrFormat::Svg,
+                _ => return Err(QrError::InvalidFormat(format.clone())),
+            };
+        }
+        if let Some(size) = query.size {
+            self.size = size;
+        }
+        if let Some(ec) = &query.ec {
+            self.ec_level = parse_ec_level(ec)?;
+        }
+        if let Some(margin) = query.margin {
+            self.margin = margin;
+        }
+        if let Some(fg) = &query.fg {
+            self.foreground = Rgb::parse(fg)?;
+        }
+        if let Some(bg) = &query.bg {
+            self.background = Rgb::parse(bg)?;
+        }
+        self.check()
+    }
+
+    fn check(self) -> Result<Self, QrError> {
+        if !(MIN_SIZE..=MAX_SIZE).contains(&self.size) {
+            return Err(QrError::InvalidSize(self.size));
+        }
+        if self.margin > MAX_MARGIN {
+            return Err(QrError::InvalidMargin(self.margin));
+        }
+        Ok(self)
+    }
+}
+
+/// The module grid, including the quiet zone
+struct Matrix {
+    modules: Vec<Color>,
+    code_width: usize,
+    margin: usize,
+}
+
+impl Matrix {
+    fn encode(data: &str, options: &QrOptions) -> Result<Self, QrError> {
+        let code = QrCode::with_error_correction_level(data, options.ec_level).map_err(|e| QrError::Encode(e.to_string()))?;
+        Ok(Matrix { code_width: code.width(), modules: code.into_colors(), margin: options.margin as usize })
+    }
+
+    fn width(&self) -> usize {
+        self.code_width + 2 * self.margin
+    }
+
+    fn is_dark(&self, x: usize, y: usize) -> bool {
+        let (x, y) = (x.wrapping_sub(self.margin), y.wrapping_sub(self.margin));
+        x < self.code_width && y < self.code_width && self.modules[y * self.code_width + x] == Color::Dark
+    }
+}
+
+pub fn render_png(data: &str, options: &QrOptions) -> Result<Vec<u8>, QrError> {
+    let matrix = Matrix::encode(data, options)?;
+    let scale = (options.size as usize / matrix.width()).max(1);
+    let pixels = matrix.width() * scale;
+    let mut image = Vec::with_capacity(pixels * pixels * 3);
+    for y in 0..pixels {
+        for x in 0..pixels {
+            let Rgb(color) = if matrix.is_dark(x / scale, y / scale) { options.foreground } else { options.background };
+            image.extend_from_slice(&color);
+        }
+    }
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, pixels as u32, pixels as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| QrError::Encode(e.to_string()))?;
+    writer.write_image_data(&image).map_err(|e| QrError::Encode(e.to_string()))?;
+    writer.finish().map_err(|e| QrError::Encode(e.to_string()))?;
+    Ok(out)
+}
+
+pub fn render_svg(data: &str, options: &QrOptions) -> Result<String, QrError> {
+    let matrix = Matrix::encode(data, options)?;
+    let width = matrix.width();
+    let mut path = String::new();
+    for y in 0..width {
+        for x in 0..width {
+            if matrix.is_dark(x, y) {
+                let _ = write!(path, "M{},{}h1v1h-1z", x, y);
+            }
+        }
+    }
+    Ok(format!(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{size}" height="{size}" viewBox="0 0 {width} {width}" shape-rendering="crispEdges">"#,
+            r#"<rect width="{width}" height="{width}" fill="{bg}"/><path d="{path}" fill="{fg}"/></svg>"#,
+        ),
+        size = options.size,
+        width = width,
+        bg = options.background.hex(),
+        fg = options.foreground.hex(),
+        path = path,
+    ))
+}
+
+/// PNG as a `data:` URI, for embedding in API responses
+pub fn png_data_uri(data: &str, options: &QrOptions) -> Result<String, QrError> {
+    let png = render_png(data, options)?;
+    Ok(format!("data:image/png;base64,{}", base64::engine::general_purpose::STANDARD.encode(png)))
+}
+
+pub async fn qr_code(
+    client: web::Data<Client>,
+    defaults: web::Data<QrOptions>,
+    public_url: web::Data<PublicUrl>,
+    domains: web::Data<Domains>,
+    path: web::Path<String>,
+    query: web::Query<QrQuery>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse> {
+    let short_code = path.into_inner();
+    let options = match defaults.as_ref().clone().with_query(&query) {
+        Ok(options) => options,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+    let domain = match domains.by_name(query.domain.as_deref()) {
+        Ok(domain) => domain,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+    let collection: Collection<UrlDoc> = client.database("shortener").collection("urls");
+    // No codes for disabled or deleted links
+    let filter = doc! {"short_code": &short_code, "domain": domain.filter_value(), "status": null};
+    let exists = collection.find_one(filter, None).await.map_err(|e| {
+        actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e))
+    })?;
+    if exists.is_none() {
+        return Ok(HttpResponse::NotFound().body("Short URL not found"));
+    }
+    let short_url = domain.short_url(&public_url, &http_req, &short_code);
+    let response = match options.format {
+        QrFormat::Png => render_png(&short_url, &options).map(|png| HttpResponse::Ok().content_type("image/png").body(png)),
+        QrFormat::Svg => render_svg(&short_url, &options).map(|svg| HttpResponse::Ok().content_type("image/svg+xml").body(svg)),
+    };
+    response.map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> QrOptions {
+        QrOptions::from_config(&QrConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_colors() {
+        assert_eq!(Rgb::parse("#1a2B3c").unwrap(), Rgb([0x1a, 0x2b, 0x3c]));
+        assert_eq!(Rgb::parse("f00").unwrap(), Rgb([0xff, 0, 0]));
+        assert!(Rgb::parse("#12345").is_err());
+        assert!(Rgb::parse("zzzzzz").is_err());
+        assert!(Rgb::parse("é00").is_err());
+    }
+
+    #[test]
+    fn test_query_overrides() {
+        let query = QrQuery { format: Some("SVG".into()), ec: Some("h".into()), fg: Some("#00f".into()), ..Default::default() };
+        let options = options().with_query(&query).unwrap();
+        assert_eq!(options.format, QrFormat::Svg);
+        assert_eq!(options.ec_level, EcLevel::H);
+        assert_eq!(options.foreground, Rgb([0, 0, 0xff]));
+        assert_eq!(options.size, 256);
+        let too_big = QrQuery { size: Some(10_000), ..Default::default() };
+        assert_eq!(options.clone().with_query(&too_big).unwrap_err(), QrError::InvalidSize(10_000));
+        let bad_format = QrQuery { format: Some("gif".into()), ..Default::default() };
+        assert!(options.with_query(&bad_format).is_err());
+    }
+
+    #[test]
+    fn test_png_dimensions() {
+        let options = options();
+        let png = render_png("https://sho.rt/abc1234", &options).unwrap();
+        let decoder = png::Decoder::new(png.as_slice());
+        let reader = decoder.read_info().unwrap();
+        let (width, height) = reader.info().size();
+        assert_eq!(width, height);
+        // Whole pixels per module, never larger than requested
+        assert!(width <= options.size && width > options.size / 2);
+    }
+
+    #[test]
+    fn test_svg_uses_colors_and_margin() {
+        let query = QrQuery { fg: Some("#112233".into()), bg: Some("#fff".into()), margin: Some(2), ..Default::default() };
+        let options = options().with_query(&query).unwrap();
+        let svg = render_svg("https://sho.rt/abc1234", &options).unwrap();
+        assert!(svg.contains(r##"fill="#112233""##));
+        assert!(svg.contains(r##"fill="#ffffff""##));
+        // Version 2 code (25 modules) plus a 2 module margin on each side
+        assert!(svg.contains(r#"viewBox="0 0 29 29""#));
+        assert!(svg.contains("M2,2h1v1h-1z"));
+    }
+
+    #[test]
+    fn test_data_uri() {
+        let uri = png_data_uri("https://sho.rt/abc1234", &options()).unwrap();
+        assert!(uri.starts_with("data:image/png;base64,iVBORw0KGgo"));
+    }
+}
@@ -0,0 +1,239 @@
+//! Redirect Cache Module
+//!
+//! In-process LRU cache of `(domain, short_code)` → destination in front of
+//! MongoDB for the redirect path. Unknown codes are cached too (negative
+//! caching) with a shorter TTL. Entries are invalidated locally when a link
+//! changes; other instances see the change once their TTL expires.
+
+use lru::LruCache;
+use mongodb::bson::oid::ObjectId;
+use serde::Serialize;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::CacheConfig;
+use crate::links::LinkStatus;
+use crate::passthrough::Passthrough;
+use crate::redirect_type::RedirectType;
+
+/// The parts of a link the redirect path needs
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedLink {
+    pub id: ObjectId,
+    pub original_url: String,
+    pub redirect_type: RedirectType,
+    pub passthrough: Passthrough,
+    /// Disabled and deleted links are cached too, to answer with the inactive page
+    pub status: LinkStatus,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    Hit(CachedLink),
+    /// The code is known not to exist
+    NegativeHit,
+    Miss,
+}
+
+struct Entry {
+    link: Option<CachedLink>,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub negative_hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+}
+
+pub struct RedirectCache {
+    /// `None` when caching is disabled
+    entries: Option<Mutex<LruCache<(String, String), Entry>>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl RedirectCache {
+    /// A `capacity` of zero disables the cache
+    pub fn new(capacity: usize, ttl: Duration, negative_ttl: Duration) -> Self {
+        RedirectCache {
+            entries: NonZeroUsize::new(capacity).map(|c| Mutex::new(LruCache::new(c))),
+            ttl,
+            negative_ttl,
+            hits: AtomicU64::new(0),
+            negative_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+        }
+    }
+
+    pub fn from_config(config: &CacheConfig) -> Self {
+        Self::new(
+            config.capacity,
+            Duration::from_secs(config.ttl_secs),
+            Duration::from_secs(config.negative_ttl_secs),
+        )
+    }
+
+    pub fn get(&self, domain: &str, short_code: &str) -> Lookup {
+        self.get_at(domain, short_code, Instant::now())
+    }
+
+    fn get_at(&self, domain: &str, short_code: &str, now: Instant) -> Lookup {
+        let Some(entries) = &self.entries else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Lookup::Miss;
+        };
+        let key = (domain.to_string(), short_code.to_string());
+        let mut entries = entries.lock().unwrap();
+        let lookup = match entries.get(&key) {
+            Some(entry) if entry.expires_at > now => match &entry.link {
+                Some(link) => Lookup::Hit(link.clone()),
+                None => Lookup::NegativeHit,
+            },
+            Some(_) => {
+                entries.pop(&key);
+                Lookup::Miss
+            }
+            None => Lookup::Miss,
+        };
+        let counter = match lookup {
+            Lookup::Hit(_) => &self.hits,
+            Lookup::NegativeHit => &self.negative_hits,
+            Lookup::Miss => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        lookup
+    }
+
+    /// Remember the result of a database lookup; `None` records that the code does not exist
+    pub fn insert(&self, domain: &str, short_code: &str, link: Option<CachedLink>) {
+        self.insert_at(domain, short_code, link, Instant::now())
+    }
+
+    fn insert_at(&self, domain: &str, short_code: &str, link: Option<CachedLink>, now: Instant) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+        let ttl = if link.is_some() { self.ttl } else { self.negative_ttl };
+        if ttl.is_zero() {
+            return;
+        }
+        let key = (domain.to_string(), short_code.to_string());
+        let evicted = entries.lock().unwrap().push(key.clone(), Entry { link, expires_at: now + ttl });
+        if matches!(evicted, Some((evicted_key, _)) if evicted_key != key) {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Drop a cached entry after the link was created, edited or deleted
+    pub fn invalidate(&self, domain: &str, short_code: &str) {
+        if let Some(entries) = &self.entries {
+            let key = (domain.to_string(), short_code.to_string());
+            if entries.lock().unwrap().pop(&key).is_some() {
+                self.invalidations.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (capacity, entries) = match &self.entries {
+            Some(entries) => {
+                let entries = entries.lock().unwrap();
+                (entries.cap().get(), entries.len())
+            }
+            None => (0, 0),
+        };
+        CacheStats {
+            capacity,
+            entries,
+            hits: self.hits.load(Ordering::Relaxed),
+            negative_hits: self.negative_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(url: &str) -> CachedLink {
+        CachedLink { id: ObjectId::new(), original_url: url.into(), redirect_type: RedirectType::Found, passthrough: Passthrough::Off, status: LinkStatus::Active }
+    }
+
+    fn cache(capacity: usize) -> RedirectCache {
+        RedirectCache::new(capacity, Duration::from_secs(60), Duration::from_secs(5))
+    }
+
+    #[test]
+    fn test_hit_and_miss() {
+        let cache = cache(10);
+        assert_eq!(cache.get("default", "abc"), Lookup::Miss);
+        let cached = link("https://example.com");
+        cache.insert("default", "abc", Some(cached.clone()));
+        assert_eq!(cache.get("default", "abc"), Lookup::Hit(cached));
+        // Codes are namespaced per domain
+        assert_eq!(cache.get("brand-b.link", "abc"), Lookup::Miss);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 1));
+    }
+
+    #[test]
+    fn test_negative_entries_expire_sooner() {
+        let cache = cache(10);
+        let now = Instant::now();
+        cache.insert_at("default", "known", Some(link("https://example.com")), now);
+        cache.insert_at("default", "unknown", None, now);
+        assert_eq!(cache.get_at("default", "unknown", now), Lookup::NegativeHit);
+        let later = now + Duration::from_secs(10);
+        assert_eq!(cache.get_at("default", "unknown", later), Lookup::Miss);
+        assert!(matches!(cache.get_at("default", "known", later), Lookup::Hit(_)));
+        assert_eq!(cache.get_at("default", "known", now + Duration::from_secs(61)), Lookup::Miss);
+        assert_eq!(cache.stats().negative_hits, 1);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let cache = cache(10);
+        cache.insert("default", "abc", None);
+        cache.invalidate("default", "abc");
+        assert_eq!(cache.get("default", "abc"), Lookup::Miss);
+        assert_eq!(cache.stats().invalidations, 1);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = cache(2);
+        cache.insert("default", "a", Some(link("https://a.example")));
+        cache.insert("default", "b", Some(link("https://b.example")));
+        assert!(matches!(cache.get("default", "a"), Lookup::Hit(_)));
+        cache.insert("default", "c", Some(link("https://c.example")));
+        assert_eq!(cache.get("default", "b"), Lookup::Miss);
+        assert!(matches!(cache.get("default", "a"), Lookup::Hit(_)));
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn test_disabled_cache() {
+        let cache = cache(0);
+        cache.insert("default", "abc", Some(link("https://example.com")));
+        assert_eq!(cache.get("default", "abc"), Lookup::Miss);
+        assert_eq!(cache.stats().capacity, 0);
+    }
+}
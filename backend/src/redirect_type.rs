@@ -0,0 +1,84 @@
+//! Redirect Type Module
+//!
+//! The HTTP status a short link answers with. Stored per link as the numeric
+//! status code; links created before this setting existed redirect with 302.
+
+use actix_web::http::StatusCode;
+use mongodb::bson::{bson, Bson};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u16", into = "u16")]
+pub enum RedirectType {
+    /// 301, permanent; clients may switch POST to GET
+    MovedPermanently,
+    /// 302, temporary; clients may switch POST to GET
+    #[default]
+    Found,
+    /// 307, temporary; method and body are preserved
+    TemporaryRedirect,
+    /// 308, permanent; method and body are preserved
+    PermanentRedirect,
+}
+
+impl RedirectType {
+    pub fn status(self) -> StatusCode {
+        match self {
+            RedirectType::MovedPermanently => StatusCode::MOVED_PERMANENTLY,
+            RedirectType::Found => StatusCode::FOUND,
+            RedirectType::TemporaryRedirect => StatusCode::TEMPORARY_REDIRECT,
+            RedirectType::PermanentRedirect => StatusCode::PERMANENT_REDIRECT,
+        }
+    }
+
+    /// Value to match `redirect_type` against in Mongo filters; 302 also matches links without the field
+    pub fn filter_value(self) -> Bson {
+        match self {
+            RedirectType::Found => bson!({"$in": [302, Bson::Null]}),
+            other => Bson::Int32(u16::from(other) as i32),
+        }
+    }
+}
+
+impl TryFrom<u16> for RedirectType {
+    type Error = String;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        match code {
+            301 => Ok(RedirectType::MovedPermanently),
+            302 => Ok(RedirectType::Found),
+            307 => Ok(RedirectType::TemporaryRedirect),
+            308 => Ok(RedirectType::PermanentRedirect),
+            other => Err(format!("unsupported redirect type {}, expected 301, 302, 307 or 308", other)),
+        }
+    }
+}
+
+impl From<RedirectType> for u16 {
+    fn from(redirect_type: RedirectType) -> u16 {
+        redirect_type.status().as_u16()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for code in [301u16, 302, 307, 308] {
+            let redirect_type = RedirectType::try_from(code).unwrap();
+            assert_eq!(u16::from(redirect_type), code);
+            assert_eq!(redirect_type.status().as_u16(), code);
+        }
+        assert!(RedirectType::try_from(303).is_err());
+    }
+
+    #[test]
+    fn test_serde_as_number() {
+        let parsed: RedirectType = serde_json::from_str("308").unwrap();
+        assert_eq!(parsed, RedirectType::PermanentRedirect);
+        assert_eq!(serde_json::to_string(&RedirectType::MovedPermanently).unwrap(), "301");
+        assert!(serde_json::from_str::<RedirectType>("200").is_err());
+    }
+}
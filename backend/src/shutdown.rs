@@ -0,0 +1,79 @@
+//! Graceful Shutdown Module
+//!
+//! Work that has to finish after the HTTP server stopped accepting requests
+//! and drained the in-flight ones: flushing buffered writes, exporting the
+//! remaining trace spans. Hooks run in registration order and share a single
+//! deadline, so a stuck hook cannot hold the process past its grace period.
+
+use futures::future::LocalBoxFuture;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+
+type Hook = Box<dyn FnOnce() -> LocalBoxFuture<'static, ()>>;
+
+#[derive(Default)]
+pub struct ShutdownHooks {
+    hooks: Vec<(&'static str, Hook)>,
+}
+
+impl ShutdownHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<F, Fut>(&mut self, name: &'static str, hook: F)
+    where
+        F: FnOnce() -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.hooks.push((name, Box::new(move || Box::pin(hook()))));
+    }
+
+    /// Run every hook within `timeout`; returns `false` if any of them did not finish in time
+    pub async fn run(self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut completed = true;
+        for (name, hook) in self.hooks {
+            match timeout_at(deadline, hook()).await {
+                Ok(()) => tracing::info!(hook = name, "Shutdown hook completed"),
+                Err(_) => {
+                    tracing::warn!(hook = name, "Shutdown hook did not complete before the deadline");
+                    completed = false;
+                }
+            }
+        }
+        completed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[actix_rt::test]
+    async fn test_hooks_run_in_order() {
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let mut hooks = ShutdownHooks::new();
+        for name in ["clicks", "tracer"] {
+            let order = order.clone();
+            hooks.register(name, move || async move { order.borrow_mut().push(name) });
+        }
+        assert!(hooks.run(Duration::from_secs(1)).await);
+        assert_eq!(*order.borrow(), vec!["clicks", "tracer"]);
+    }
+
+    #[actix_rt::test]
+    async fn test_deadline_is_shared() {
+        let ran_last = Rc::new(RefCell::new(false));
+        let mut hooks = ShutdownHooks::new();
+        hooks.register("slow", || tokio::time::sleep(Duration::from_secs(5)));
+        let flag = ran_last.clone();
+        hooks.register("fast", move || async move { *flag.borrow_mut() = true });
+        assert!(!hooks.run(Duration::from_millis(50)).await);
+        // The fast hook still gets polled once and finishes immediately
+        assert!(*ran_last.borrow());
+    }
+}
@@ -3,17 +3,24 @@ use opentelemetry::sdk::propagation::TraceContextPropagator;
 use tracing::info;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use crate::config::TracingConfig;
 
-pub fn init_tracer() -> Result<(), Box<dyn std::error::Error>> {
+pub fn init_tracer(config: &TracingConfig) -> Result<(), Box<dyn std::error::Error>> {
     global::set_text_map_propagator(TraceContextPropagator::new());
-    let service_name = std::env::var("SERVICE_NAME").unwrap_or_else(|_| "url-shortener".into());
-    let jaeger_endpoint = std::env::var("JAEGER_ENDPOINT").unwrap_or_else(|_| "http://localhost:14268/api/traces".into());
     let tracer = opentelemetry_jaeger::new_agent_pipeline()
-        .with_service_name(service_name)
-        .with_endpoint(jaeger_endpoint)
+        .with_service_name(config.service_name.clone())
+        .with_endpoint(config.jaeger_endpoint.clone())
         .install_batch(opentelemetry::runtime::Tokio)?;
     let telemetry_layer = tracing_opentelemetry::layer().with_tracer(tracer);
     tracing_subscriber::registry().with(telemetry_layer).try_init()?;
     info!("OpenTelemetry tracing initialized");
     Ok(())
 }
+
+/// Flush pending spans from the batch exporter and uninstall the global tracer provider
+pub async fn shutdown_tracer() {
+    // Dropping the provider blocks until the batch processor has exported, so keep it off the runtime thread
+    if let Err(e) = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await {
+        tracing::error!("Failed to shut down tracer provider: {:?}", e);
+    }
+}
@@ -2,20 +2,112 @@
 //!
 //! Provides core functionality for creating and managing short URLs, including
 //! URL validation/normalization.
+//!
+//! Destinations must not point into the network the service runs in. IP
+//! literals are canonicalized by the URL parser (so `http://2130706433` and
+//! `http://0177.0.0.1` are both `127.0.0.1`) and rejected when they fall in a
+//! loopback, private, link-local, CGNAT, multicast or otherwise reserved
+//! range; IPv6 addresses embedding an IPv4 one are judged by that address.
+//! With a [`Resolver`], host names are also looked up and rejected when any
+//! of their addresses is non-public. The lookup only reflects DNS at the time
+//! of shortening.
 
+use futures::{stream, StreamExt};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::sync::Arc;
 use thiserror::Error;
-use url::Url;
+use url::{Host, Url};
+
+use crate::config::LinksConfig;
 
 /// Maximum allowed URL length
 const MAX_URL_LENGTH: usize = 2048;
-/// Example disallowed domains (can be expanded/configured)
-const DISALLOWED_DOMAINS: &[&str] = &["localhost", "127.0.0.1", "::1"];
+/// Host lookups run at once when checking many destinations
+const MAX_CONCURRENT_LOOKUPS: usize = 16;
+
+/// Looks up the addresses of a host name
+pub trait Resolver: Send + Sync {
+    fn lookup(&self, host: &str) -> std::io::Result<Vec<IpAddr>>;
+}
 
-pub struct UrlService;
+/// The operating system's resolver
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn lookup(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        Ok((host, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect())
+    }
+}
+
+/// Whether an IPv4 address is routable on the public internet
+pub fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(a == 0 // "this network"
+        || a == 10 // private
+        || (a == 100 && (64..128).contains(&b)) // CGNAT
+        || a == 127 // loopback
+        || (a == 169 && b == 254) // link-local
+        || (a == 172 && (16..32).contains(&b)) // private
+        || (a == 192 && b == 0 && c == 0) // IETF protocol assignments
+        || (a == 192 && b == 0 && c == 2) // documentation
+        || (a == 192 && b == 88 && c == 99) // 6to4 relay anycast
+        || (a == 192 && b == 168) // private
+        || (a == 198 && (b == 18 || b == 19)) // benchmarking
+        || (a == 198 && b == 51 && c == 100) // documentation
+        || (a == 203 && b == 0 && c == 113) // documentation
+        || a >= 224) // multicast, reserved and broadcast
+}
+
+/// Whether an IPv6 address is routable on the public internet
+pub fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    let embedded = |high: usize| Ipv4Addr::from(((segments[high] as u32) << 16) | segments[high + 1] as u32);
+    match segments {
+        // Unspecified, loopback, IPv4-compatible and IPv4-mapped
+        [0, 0, 0, 0, 0, 0, _, _] | [0, 0, 0, 0, 0, 0xffff, _, _] => segments[6] != 0 && is_public_ipv4(embedded(6)),
+        // NAT64
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => is_public_ipv4(embedded(6)),
+        [0x64, 0xff9b, 1, ..] => false,
+        // 6to4
+        [0x2002, ..] => is_public_ipv4(embedded(1)),
+        // IETF protocol assignments (Teredo, ORCHID, ...) and documentation
+        [0x2001, b, ..] if b < 0x200 => false,
+        [0x2001, 0xdb8, ..] => false,
+        [0x3fff, b, ..] if b < 0x1000 => false,
+        // Only global unicast remains public: not unique local, link-local, multicast or unassigned
+        [a, ..] => a & 0xe000 == 0x2000,
+    }
+}
+
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip),
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct UrlService {
+    /// Host names are only resolved when set
+    resolver: Option<Arc<dyn Resolver>>,
+}
 
 impl UrlService {
     pub fn new_dummy() -> Self {
-        UrlService
+        UrlService::default()
+    }
+
+    pub fn with_resolver(resolver: Arc<dyn Resolver>) -> Self {
+        UrlService { resolver: Some(resolver) }
+    }
+
+    pub fn from_config(config: &LinksConfig) -> Self {
+        if config.resolve_destinations {
+            UrlService::with_resolver(Arc::new(SystemResolver))
+        } else {
+            UrlService::new_dummy()
+        }
     }
 
     /// Validate a URL string for format, protocol, length, and disallowed domains
@@ -32,13 +124,72 @@ impl UrlService {
         if scheme != "http" && scheme != "https" {
             return Err(UrlServiceError::InvalidUrl("URL must use http or https".into()));
         }
-        let host = parsed.host_str().unwrap_or("").to_ascii_lowercase();
-        for &bad in DISALLOWED_DOMAINS {
-            if host == bad {
-                return Err(UrlServiceError::InvalidUrl("Disallowed domain".into()));
+        match parsed.host() {
+            Some(Host::Ipv4(ip)) if !is_public_ipv4(ip) => Err(UrlServiceError::InvalidUrl("Disallowed address".into())),
+            Some(Host::Ipv6(ip)) if !is_public_ipv6(ip) => Err(UrlServiceError::InvalidUrl("Disallowed address".into())),
+            Some(Host::Domain(host)) => {
+                let host = host.trim_end_matches('.').to_ascii_lowercase();
+                if host == "localhost" || host.ends_with(".localhost") {
+                    return Err(UrlServiceError::InvalidUrl("Disallowed domain".into()));
+                }
+                Ok(())
             }
+            Some(_) => Ok(()),
+            None => Err(UrlServiceError::InvalidUrl("URL has no host".into())),
+        }
+    }
+
+    /// Resolve the host names of already validated URLs, in order: an error for
+    /// each URL whose host has a non-public address. Hosts that do not resolve
+    /// pass, as links may be created before their destination goes live.
+    pub async fn check_resolved(&self, urls: &[&str]) -> Vec<Option<UrlServiceError>> {
+        let Some(resolver) = &self.resolver else {
+            return urls.iter().map(|_| None).collect();
+        };
+        let hosts: Vec<Option<String>> = urls
+            .iter()
+            .map(|url| match Url::parse(url).ok()?.host()? {
+                Host::Domain(host) => Some(host.trim_end_matches('.').to_ascii_lowercase()),
+                _ => None,
+            })
+            .collect();
+        let mut unique: Vec<String> = hosts.iter().flatten().cloned().collect();
+        unique.sort();
+        unique.dedup();
+        let allowed: HashMap<String, bool> = stream::iter(unique)
+            .map(|host| {
+                let resolver = resolver.clone();
+                async move {
+                    let lookup_host = host.clone();
+                    let allowed = match tokio::task::spawn_blocking(move || resolver.lookup(&lookup_host)).await {
+                        Ok(Ok(addresses)) => addresses.into_iter().all(is_public_ip),
+                        Ok(Err(e)) => {
+                            tracing::debug!(host = %host, "Destination host does not resolve: {}", e);
+                            true
+                        }
+                        Err(_) => true,
+                    };
+                    (host, allowed)
+                }
+            })
+            .buffer_unordered(MAX_CONCURRENT_LOOKUPS)
+            .collect()
+            .await;
+        hosts
+            .into_iter()
+            .map(|host| {
+                host.filter(|host| !allowed.get(host).copied().unwrap_or(true))
+                    .map(|_| UrlServiceError::InvalidUrl("Destination resolves to a disallowed address".into()))
+            })
+            .collect()
+    }
+
+    /// [`check_resolved`](Self::check_resolved) for one URL
+    pub async fn check_resolved_url(&self, url: &str) -> Result<(), UrlServiceError> {
+        match self.check_resolved(&[url]).await.pop().flatten() {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
-        Ok(())
     }
 
     /// Normalize a URL string (lowercase scheme/host, remove default ports, trailing slash, etc.)
@@ -80,14 +231,14 @@ mod tests {
 
     #[test]
     fn test_validate_url_valid() {
-        let service = UrlService;
+        let service = UrlService::new_dummy();
         assert!(service.validate_url("https://example.com").is_ok());
         assert!(service.validate_url("http://example.com/path?query=1").is_ok());
     }
 
     #[test]
     fn test_validate_url_invalid() {
-        let service = UrlService;
+        let service = UrlService::new_dummy();
         assert!(service.validate_url("").is_err());
         assert!(service.validate_url("ftp://example.com").is_err());
         assert!(service.validate_url("localhost").is_err());
@@ -95,9 +246,76 @@ mod tests {
         assert!(service.validate_url(&long_url).is_err());
     }
 
+    #[test]
+    fn test_validate_url_rejects_internal_addresses() {
+        let service = UrlService::new_dummy();
+        for url in [
+            "http://10.0.0.5",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.1.1",
+            "http://172.31.255.255",
+            "http://192.168.1.1:8080",
+            "http://0.0.0.0",
+            "http://224.0.0.1",
+            "http://255.255.255.255",
+            "http://127.0.0.1",
+            // Other spellings of 127.0.0.1
+            "http://2130706433",
+            "http://0177.0.0.1",
+            "http://0x7f.1",
+            "http://127.1",
+            "http://[::1]",
+            "http://[::ffff:127.0.0.1]",
+            "http://[::ffff:7f00:1]",
+            "http://[64:ff9b::a9fe:a9fe]",
+            "http://[2002:c0a8:101::1]",
+            "http://[fd00::1]",
+            "http://[fe80::1]",
+            "http://[ff02::1]",
+            "http://[2001:db8::1]",
+            "http://[::]",
+            "http://localhost",
+            "http://LOCALHOST.:3000",
+            "http://api.localhost",
+        ] {
+            assert!(service.validate_url(url).is_err(), "{} should be rejected", url);
+        }
+    }
+
+    #[test]
+    fn test_validate_url_allows_public_addresses() {
+        let service = UrlService::new_dummy();
+        for url in ["http://8.8.8.8", "http://[2606:4700::1111]", "http://[::ffff:8.8.8.8]", "http://172.32.0.1", "http://localhost.example.com"] {
+            assert!(service.validate_url(url).is_ok(), "{} should be allowed", url);
+        }
+    }
+
+    struct StubResolver;
+
+    impl Resolver for StubResolver {
+        fn lookup(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+            match host {
+                "internal.example.com" => Ok(vec!["93.184.216.34".parse().unwrap(), "10.1.2.3".parse().unwrap()]),
+                "public.example.com" => Ok(vec!["93.184.216.34".parse().unwrap(), "2606:2800:220:1::1".parse().unwrap()]),
+                _ => Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no such host")),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_check_resolved() {
+        let service = UrlService::with_resolver(Arc::new(StubResolver));
+        let urls = ["https://public.example.com/a", "https://internal.example.com/b", "https://unknown.example.com", "https://8.8.8.8"];
+        let errors: Vec<bool> = service.check_resolved(&urls).await.iter().map(Option::is_some).collect();
+        assert_eq!(errors, vec![false, true, false, false]);
+        assert!(service.check_resolved_url("https://INTERNAL.example.com.").await.is_err());
+        // Without a resolver nothing is looked up
+        assert!(UrlService::new_dummy().check_resolved_url("https://internal.example.com").await.is_ok());
+    }
+
     #[test]
     fn test_normalize_url() {
-        let service = UrlService;
+        let service = UrlService::new_dummy();
         let url = "HTTP://EXAMPLE.COM:80/path/";
         let norm = service.normalize_url(url).unwrap();
         assert!(norm.starts_with("http://example.com/path"));
@@ -0,0 +1,114 @@
+//! UTM Module
+//!
+//! Adds `utm_*` campaign parameters to a destination before it is normalized
+//! and stored. Parameters given on the shorten request replace any already in
+//! the URL; the owner's default template only fills in parameters that are
+//! still missing. Tagged parameters are always appended in a fixed order so
+//! the same tags produce the same stored URL (and thus deduplicate).
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::Url;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UtmParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub medium: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub campaign: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub term: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum UtmError {
+    #[error("Malformed URL")]
+    InvalidUrl,
+}
+
+impl UtmParams {
+    /// Query parameter names with their values, in the order they are appended
+    fn fields(&self) -> [(&'static str, Option<&str>); 5] {
+        fn value(v: &Option<String>) -> Option<&str> {
+            v.as_deref().map(str::trim).filter(|v| !v.is_empty())
+        }
+        [
+            ("utm_source", value(&self.source)),
+            ("utm_medium", value(&self.medium)),
+            ("utm_campaign", value(&self.campaign)),
+            ("utm_term", value(&self.term)),
+            ("utm_content", value(&self.content)),
+        ]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields().iter().all(|(_, v)| v.is_none())
+    }
+
+    /// Tag `url` with these parameters, falling back to `template` for ones not set here or in the URL
+    pub fn apply(&self, url: &str, template: &UtmParams) -> Result<String, UtmError> {
+        if self.is_empty() && template.is_empty() {
+            return Ok(url.to_string());
+        }
+        let mut parsed = Url::parse(url).map_err(|_| UtmError::InvalidUrl)?;
+        let mut pairs: Vec<(String, String)> = parsed.query_pairs().into_owned().collect();
+        let mut tags = Vec::new();
+        for ((name, explicit), (_, default)) in self.fields().into_iter().zip(template.fields()) {
+            let existing = pairs.iter().position(|(k, _)| k == name);
+            let value = match (explicit, existing) {
+                (Some(value), _) => value.to_string(),
+                (None, Some(i)) => pairs[i].1.clone(),
+                (None, None) => match default {
+                    Some(value) => value.to_string(),
+                    None => continue,
+                },
+            };
+            pairs.retain(|(k, _)| k != name);
+            tags.push((name, value));
+        }
+        parsed.query_pairs_mut().clear().extend_pairs(pairs).extend_pairs(tags);
+        Ok(parsed.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(source: &str, campaign: &str) -> UtmParams {
+        UtmParams { source: Some(source.into()), campaign: Some(campaign.into()), ..Default::default() }
+    }
+
+    #[test]
+    fn test_untagged_url_unchanged() {
+        let url = "https://example.com/p?b=2&a=1";
+        assert_eq!(UtmParams::default().apply(url, &UtmParams::default()).unwrap(), url);
+    }
+
+    #[test]
+    fn test_tags_appended_in_fixed_order() {
+        let utm = UtmParams { content: Some("hero".into()), ..params("newsletter", "spring sale") };
+        let tagged = utm.apply("https://example.com/p?a=1", &UtmParams::default()).unwrap();
+        assert_eq!(tagged, "https://example.com/p?a=1&utm_source=newsletter&utm_campaign=spring+sale&utm_content=hero");
+    }
+
+    #[test]
+    fn test_precedence() {
+        let url = "https://example.com/?utm_source=manual&utm_medium=email&x=1";
+        let template = UtmParams { medium: Some("social".into()), term: Some("links".into()), ..params("template", "t") };
+        let tagged = UtmParams { campaign: Some("launch".into()), ..Default::default() }.apply(url, &template).unwrap();
+        // Request beats URL beats template
+        assert_eq!(tagged, "https://example.com/?x=1&utm_source=manual&utm_medium=email&utm_campaign=launch&utm_term=links");
+    }
+
+    #[test]
+    fn test_blank_values_ignored() {
+        let utm = params("  ", "");
+        assert!(utm.is_empty());
+    }
+}
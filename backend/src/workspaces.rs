@@ -0,0 +1,493 @@
+//! Workspaces Module
+//!
+//! Workspaces let several teams share one deployment. A workspace owns links
+//! and has members with a role:
+//!
+//! - `viewer` reads the analytics of the workspace's links
+//! - `editor` also creates links in the workspace and edits, disables and
+//!   deletes them
+//! - `owner` also manages members and invitations
+//!
+//! Members are embedded in the workspace document. Links created in a
+//! workspace carry its id as `workspace_id` and `owner_id`, so API keys whose
+//! owner is the workspace id act for the workspace as well. People join with
+//! single-use invitation tokens (`lsi_<32 characters>`), stored hashed like
+//! session tokens.
+
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpResponse, ResponseError, Result};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, DateTime as MongoDateTime};
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions};
+use mongodb::{Client, Collection};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::accounts::{AccountError, User};
+use crate::api_keys::{hash_key, random_alphanumeric};
+use crate::config::Config;
+use crate::links::rfc3339;
+
+const TOKEN_PREFIX: &str = "lsi_";
+const TOKEN_LENGTH: usize = 32;
+const MAX_NAME_LENGTH: usize = 100;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum WorkspaceError {
+    #[error("Invalid workspace id")]
+    InvalidId,
+    #[error("Workspace names must be 1 to {MAX_NAME_LENGTH} characters")]
+    InvalidName,
+    #[error("Workspace not found")]
+    NotFound,
+    #[error("This requires the {0} role in the workspace")]
+    RoleRequired(&'static str),
+    #[error("A workspace must keep at least one owner")]
+    LastOwner,
+    #[error("Already a member of this workspace")]
+    AlreadyMember,
+    #[error("Member not found")]
+    MemberNotFound,
+    #[error("Invalid, used or expired invitation")]
+    InvalidInvitation,
+}
+
+impl ResponseError for WorkspaceError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            WorkspaceError::InvalidId | WorkspaceError::InvalidName | WorkspaceError::InvalidInvitation => {
+                StatusCode::BAD_REQUEST
+            }
+            WorkspaceError::NotFound | WorkspaceError::MemberNotFound => StatusCode::NOT_FOUND,
+            WorkspaceError::RoleRequired(_) => StatusCode::FORBIDDEN,
+            WorkspaceError::LastOwner | WorkspaceError::AlreadyMember => StatusCode::CONFLICT,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).body(self.to_string())
+    }
+}
+
+/// Ordered from least to most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Editor,
+    Owner,
+}
+
+impl Role {
+    fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        }
+    }
+
+    /// Fails unless the role is at least `required`
+    pub fn require(self, required: Role) -> Result<(), WorkspaceError> {
+        if self >= required {
+            Ok(())
+        } else {
+            Err(WorkspaceError::RoleRequired(required.as_str()))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Member {
+    pub user_id: ObjectId,
+    pub role: Role,
+    pub joined_at: MongoDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkspaceDoc {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub name: String,
+    pub created_at: MongoDateTime,
+    pub members: Vec<Member>,
+}
+
+impl WorkspaceDoc {
+    pub fn role_of(&self, user_id: ObjectId) -> Option<Role> {
+        self.members.iter().find(|member| member.user_id == user_id).map(|member| member.role)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InvitationDoc {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// Hex SHA-256 of the invitation token
+    pub token_hash: String,
+    pub workspace_id: ObjectId,
+    pub role: Role,
+    pub created_by: ObjectId,
+    pub created_at: MongoDateTime,
+    /// Removed by a TTL index once passed
+    pub expires_at: MongoDateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accepted_by: Option<ObjectId>,
+}
+
+fn workspaces(client: &Client) -> Collection<WorkspaceDoc> {
+    client.database("shortener").collection("workspaces")
+}
+
+fn invitations(client: &Client) -> Collection<InvitationDoc> {
+    client.database("shortener").collection("invitations")
+}
+
+fn query_error(e: mongodb::error::Error) -> Error {
+    actix_web::error::ErrorInternalServerError(format!("Query Error: {}", e))
+}
+
+pub fn parse_id(id: &str) -> Result<ObjectId, WorkspaceError> {
+    ObjectId::parse_str(id).map_err(|_| WorkspaceError::InvalidId)
+}
+
+/// The user's role in the workspace; `None` for non-members and unknown workspaces
+pub async fn role_of(client: &Client, workspace_id: ObjectId, user_id: ObjectId) -> Result<Option<Role>, mongodb::error::Error> {
+    let workspace = workspaces(client).find_one(doc! {"_id": workspace_id, "members.user_id": user_id}, None).await?;
+    Ok(workspace.and_then(|workspace| workspace.role_of(user_id)))
+}
+
+/// Check the caller may create links in a workspace: a key owned by the workspace, or an editor
+pub async fn authorize_shorten(
+    client: &Client,
+    workspace_id: &str,
+    user: Option<&User>,
+    key_owner: Option<&str>,
+) -> Result<ObjectId, Error> {
+    let workspace_id = parse_id(workspace_id)?;
+    if key_owner == Some(workspace_id.to_hex().as_str()) {
+        return Ok(workspace_id);
+    }
+    let user = user.ok_or(AccountError::NotLoggedIn)?;
+    let role = role_of(client, workspace_id, user.id).await.map_err(query_error)?.ok_or(WorkspaceError::NotFound)?;
+    role.require(Role::Editor)?;
+    Ok(workspace_id)
+}
+
+/// The workspace, if the user is a member with at least `required`; non-members get `NotFound`
+async fn load_for(client: &Client, id: &str, user: &User, required: Role) -> Result<WorkspaceDoc, Error> {
+    let workspace = workspaces(client)
+        .find_one(doc! {"_id": parse_id(id)?, "members.user_id": user.id}, None)
+        .await
+        .map_err(query_error)?
+        .ok_or(WorkspaceError::NotFound)?;
+    workspace.role_of(user.id).ok_or(WorkspaceError::NotFound)?.require(required)?;
+    Ok(workspace)
+}
+
+fn logged_in(user: Option<web::ReqData<User>>) -> Result<User, AccountError> {
+    user.map(web::ReqData::into_inner).ok_or(AccountError::NotLoggedIn)
+}
+
+fn check_name(name: &str) -> Result<String, WorkspaceError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(WorkspaceError::InvalidName);
+    }
+    Ok(name.to_string())
+}
+
+#[derive(Serialize)]
+pub struct MemberResponse {
+    user_id: String,
+    role: Role,
+    joined_at: String,
+}
+
+#[derive(Serialize)]
+pub struct WorkspaceResponse {
+    id: String,
+    name: String,
+    created_at: String,
+    /// The caller's role
+    role: Role,
+    /// Only listed for a single workspace
+    #[serde(skip_serializing_if = "Option::is_none")]
+    members: Option<Vec<MemberResponse>>,
+}
+
+impl WorkspaceResponse {
+    fn new(workspace: WorkspaceDoc, user: &User, with_members: bool) -> Self {
+        WorkspaceResponse {
+            id: workspace.id.map(|id| id.to_hex()).unwrap_or_default(),
+            role: workspace.role_of(user.id).unwrap_or(Role::Viewer),
+            name: workspace.name,
+            created_at: rfc3339(workspace.created_at),
+            members: with_members.then(|| {
+                workspace
+                    .members
+                    .into_iter()
+                    .map(|member| MemberResponse {
+                        user_id: member.user_id.to_hex(),
+                        role: member.role,
+                        joined_at: rfc3339(member.joined_at),
+                    })
+                    .collect()
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateWorkspaceRequest {
+    name: String,
+}
+
+/// `POST /api/workspaces`: the creator becomes its owner
+pub async fn create_workspace(
+    client: web::Data<Client>,
+    user: Option<web::ReqData<User>>,
+    req: web::Json<CreateWorkspaceRequest>,
+) -> Result<HttpResponse> {
+    let user = logged_in(user)?;
+    let now = MongoDateTime::now();
+    let mut workspace = WorkspaceDoc {
+        id: None,
+        name: check_name(&req.name)?,
+        created_at: now,
+        members: vec![Member { user_id: user.id, role: Role::Owner, joined_at: now }],
+    };
+    let result = workspaces(&client)
+        .insert_one(&workspace, None)
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Insert Error: {}", e)))?;
+    workspace.id = result.inserted_id.as_object_id();
+    Ok(HttpResponse::Created().json(WorkspaceResponse::new(workspace, &user, true)))
+}
+
+/// `GET /api/workspaces`: the workspaces the caller belongs to
+pub async fn list_workspaces(client: web::Data<Client>, user: Option<web::ReqData<User>>) -> Result<HttpResponse> {
+    let user = logged_in(user)?;
+    let options = FindOptions::builder().sort(doc! {"name": 1}).build();
+    let found: Vec<WorkspaceDoc> = workspaces(&client)
+        .find(doc! {"members.user_id": user.id}, options)
+        .await
+        .map_err(query_error)?
+        .try_collect()
+        .await
+        .map_err(query_error)?;
+    Ok(HttpResponse::Ok().json(found.into_iter().map(|w| WorkspaceResponse::new(w, &user, false)).collect::<Vec<_>>()))
+}
+
+/// `GET /api/workspaces/{id}`: with its members
+pub async fn get_workspace(
+    client: web::Data<Client>,
+    user: Option<web::ReqData<User>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let user = logged_in(user)?;
+    let workspace = load_for(&client, &path, &user, Role::Viewer).await?;
+    Ok(HttpResponse::Ok().json(WorkspaceResponse::new(workspace, &user, true)))
+}
+
+#[derive(Deserialize)]
+pub struct UpdateMemberRequest {
+    role: Role,
+}
+
+/// `PATCH /api/workspaces/{id}/members/{user_id}`: owners change roles
+pub async fn update_member(
+    client: web::Data<Client>,
+    user: Option<web::ReqData<User>>,
+    path: web::Path<(String, String)>,
+    req: web::Json<UpdateMemberRequest>,
+) -> Result<HttpResponse> {
+    let user = logged_in(user)?;
+    let (workspace_id, member_id) = path.into_inner();
+    let workspace = load_for(&client, &workspace_id, &user, Role::Owner).await?;
+    let member_id = parse_id(&member_id)?;
+    let current = workspace.role_of(member_id).ok_or(WorkspaceError::MemberNotFound)?;
+    let mut filter = doc! {"_id": workspace.id, "members.user_id": member_id};
+    // Demoting an owner only succeeds while another owner remains, even with concurrent changes
+    if current == Role::Owner && req.role != Role::Owner {
+        filter.insert("members", doc! {"$elemMatch": {"role": "owner", "user_id": {"$ne": member_id}}});
+    }
+    let result = workspaces(&client)
+        .update_one(filter, doc! {"$set": {"members.$[member].role": req.role.as_str()}}, member_filter(member_id))
+        .await
+        .map_err(query_error)?;
+    if result.matched_count == 0 {
+        return Err(WorkspaceError::LastOwner.into());
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Targets the array element of one member, since `members` may also appear in the filter
+fn member_filter(member_id: ObjectId) -> mongodb::options::UpdateOptions {
+    mongodb::options::UpdateOptions::builder().array_filters(vec![doc! {"member.user_id": member_id}]).build()
+}
+
+/// `DELETE /api/workspaces/{id}/members/{user_id}`: owners remove members; anyone may leave
+pub async fn remove_member(
+    client: web::Data<Client>,
+    user: Option<web::ReqData<User>>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse> {
+    let user = logged_in(user)?;
+    let (workspace_id, member_id) = path.into_inner();
+    let member_id = parse_id(&member_id)?;
+    let required = if member_id == user.id { Role::Viewer } else { Role::Owner };
+    let workspace = load_for(&client, &workspace_id, &user, required).await?;
+    let current = workspace.role_of(member_id).ok_or(WorkspaceError::MemberNotFound)?;
+    let mut filter = doc! {"_id": workspace.id};
+    if current == Role::Owner {
+        filter.insert("members", doc! {"$elemMatch": {"role": "owner", "user_id": {"$ne": member_id}}});
+    }
+    let result = workspaces(&client)
+        .update_one(filter, doc! {"$pull": {"members": {"user_id": member_id}}}, None)
+        .await
+        .map_err(query_error)?;
+    if result.matched_count == 0 {
+        return Err(WorkspaceError::LastOwner.into());
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+pub struct CreateInvitationRequest {
+    role: Role,
+}
+
+#[derive(Serialize)]
+pub struct InvitationResponse {
+    /// Only returned here; passed to `POST /api/invitations/accept`
+    token: String,
+    workspace_id: String,
+    role: Role,
+    expires_at: String,
+}
+
+/// `POST /api/workspaces/{id}/invitations`: owners invite with any role
+pub async fn create_invitation(
+    client: web::Data<Client>,
+    settings: web::Data<Config>,
+    user: Option<web::ReqData<User>>,
+    path: web::Path<String>,
+    req: web::Json<CreateInvitationRequest>,
+) -> Result<HttpResponse> {
+    let user = logged_in(user)?;
+    let workspace = load_for(&client, &path, &user, Role::Owner).await?;
+    let workspace_id = workspace.id.ok_or(WorkspaceError::NotFound)?;
+    let token = format!("{}{}", TOKEN_PREFIX, random_alphanumeric(TOKEN_LENGTH));
+    let now = MongoDateTime::now();
+    let ttl_millis = settings.accounts.invitation_ttl_hours.saturating_mul(3_600_000).min(i64::MAX as u64) as i64;
+    let invitation = InvitationDoc {
+        id: None,
+        token_hash: hash_key(&token),
+        workspace_id,
+        role: req.role,
+        created_by: user.id,
+        created_at: now,
+        expires_at: MongoDateTime::from_millis(now.timestamp_millis().saturating_add(ttl_millis)),
+        accepted_by: None,
+    };
+    invitations(&client)
+        .insert_one(&invitation, None)
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Insert Error: {}", e)))?;
+    Ok(HttpResponse::Created().json(InvitationResponse {
+        token,
+        workspace_id: workspace_id.to_hex(),
+        role: invitation.role,
+        expires_at: rfc3339(invitation.expires_at),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct AcceptInvitationRequest {
+    token: String,
+}
+
+/// `POST /api/invitations/accept`: join the invitation's workspace with its role
+pub async fn accept_invitation(
+    client: web::Data<Client>,
+    user: Option<web::ReqData<User>>,
+    req: web::Json<AcceptInvitationRequest>,
+) -> Result<HttpResponse> {
+    let user = logged_in(user)?;
+    let filter = doc! {
+        "token_hash": hash_key(req.token.trim()),
+        "accepted_by": null,
+        "expires_at": {"$gt": MongoDateTime::now()},
+    };
+    let invitation = invitations(&client).find_one(filter.clone(), None).await.map_err(query_error)?.ok_or(WorkspaceError::InvalidInvitation)?;
+    // Checked before the invitation is used up, so members can pass it on
+    if role_of(&client, invitation.workspace_id, user.id).await.map_err(query_error)?.is_some() {
+        return Err(WorkspaceError::AlreadyMember.into());
+    }
+    // Claiming the invitation atomically makes it single-use
+    invitations(&client)
+        .find_one_and_update(filter, doc! {"$set": {"accepted_by": user.id}}, FindOneAndUpdateOptions::default())
+        .await
+        .map_err(query_error)?
+        .ok_or(WorkspaceError::InvalidInvitation)?;
+    let member = doc! {"user_id": user.id, "role": invitation.role.as_str(), "joined_at": MongoDateTime::now()};
+    let result = workspaces(&client)
+        .update_one(
+            doc! {"_id": invitation.workspace_id, "members.user_id": {"$ne": user.id}},
+            doc! {"$push": {"members": member}},
+            None,
+        )
+        .await
+        .map_err(query_error)?;
+    if result.matched_count == 0 {
+        return Err(WorkspaceError::InvalidInvitation.into());
+    }
+    let workspace = workspaces(&client)
+        .find_one(doc! {"_id": invitation.workspace_id}, None)
+        .await
+        .map_err(query_error)?
+        .ok_or(WorkspaceError::NotFound)?;
+    Ok(HttpResponse::Ok().json(WorkspaceResponse::new(workspace, &user, false)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role_order() {
+        assert!(Role::Owner.require(Role::Editor).is_ok());
+        assert!(Role::Editor.require(Role::Editor).is_ok());
+        assert_eq!(Role::Viewer.require(Role::Editor), Err(WorkspaceError::RoleRequired("editor")));
+        assert_eq!(Role::Editor.require(Role::Owner), Err(WorkspaceError::RoleRequired("owner")));
+    }
+
+    #[test]
+    fn test_role_of() {
+        let (owner, viewer) = (ObjectId::new(), ObjectId::new());
+        let now = MongoDateTime::now();
+        let workspace = WorkspaceDoc {
+            id: Some(ObjectId::new()),
+            name: "Marketing".into(),
+            created_at: now,
+            members: vec![
+                Member { user_id: owner, role: Role::Owner, joined_at: now },
+                Member { user_id: viewer, role: Role::Viewer, joined_at: now },
+            ],
+        };
+        assert_eq!(workspace.role_of(owner), Some(Role::Owner));
+        assert_eq!(workspace.role_of(viewer), Some(Role::Viewer));
+        assert_eq!(workspace.role_of(ObjectId::new()), None);
+    }
+
+    #[test]
+    fn test_names_and_ids() {
+        assert_eq!(check_name("  Marketing ").unwrap(), "Marketing");
+        assert_eq!(check_name("   "), Err(WorkspaceError::InvalidName));
+        assert_eq!(check_name(&"x".repeat(MAX_NAME_LENGTH + 1)), Err(WorkspaceError::InvalidName));
+        assert_eq!(parse_id("nope"), Err(WorkspaceError::InvalidId));
+        let id = ObjectId::new();
+        assert_eq!(parse_id(&id.to_hex()), Ok(id));
+    }
+}